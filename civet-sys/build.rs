@@ -1,14 +1,109 @@
-extern crate cmake;
+extern crate cc;
 
-use cmake::Config;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// The civetweb release that civet.c and civet's bindings are written
+/// against. The submodule isn't pinned to a commit, so check what is there.
+const CIVETWEB_VERSION: &'static str = "1.9.1";
+
+fn check_civetweb() {
+    let header = "civetweb/include/civetweb.h";
+    let mut contents = String::new();
+    if File::open(header).and_then(|mut f| f.read_to_string(&mut contents))
+                         .is_err() {
+        panic!("{} not found: check out civetweb {} in civet-sys/civetweb \
+                (`git submodule update --init`)", header, CIVETWEB_VERSION);
+    }
+    let define = format!("#define CIVETWEB_VERSION \"{}\"", CIVETWEB_VERSION);
+    if !contents.contains(&define) {
+        panic!("civet-sys/civetweb is not civetweb {}, which civet needs",
+               CIVETWEB_VERSION);
+    }
+}
+
+/// Changes civet.c needs made to civetweb.c, each of which must apply
+/// exactly once. civet.c defines what they refer to.
+const PATCHES: &'static [(&'static str, &'static str)] = &[
+    // Room for what civet.c keeps per server and per connection.
+    ("struct mg_context {\n", "struct mg_context {\n\tCIVET_CONTEXT_FIELDS\n"),
+    ("struct mg_connection {\n",
+     "struct mg_connection {\n\tCIVET_CONNECTION_FIELDS\n"),
+    // Keep the request target before handle_request changes it in place,
+    // until the connection is closed.
+    ("\t\t/* 1.1. split into url and query string */\n",
+     "\t\tcivet_save_request_target(conn);\n\
+      \t\t/* 1.1. split into url and query string */\n"),
+    ("\tmg_lock_connection(conn);\n\n\tconn->must_close = 1;\n",
+     "\tmg_lock_connection(conn);\n\n\tconn->must_close = 1;\n\
+      \tcivet_free_request_target(conn);\n"),
+    // Let civet.c decide what happens to connections the master thread
+    // accepts while the queue for worker threads is full.
+    ("produce_socket(ctx, &so);", "civet_produce_socket(ctx, &so);"),
+    // Have mg_handle_form_request read the body through the reader civet
+    // hands it, rather than straight from the connection.
+    ("#include \"handle_form.inl\"\n",
+     "#define mg_read(conn, buf, len) civet_form_read(fdh, (conn), (buf), (len))\n\
+      #include \"handle_form.inl\"\n\
+      #undef mg_read\n"),
+];
+
+/// Writes civetweb.c with `PATCHES` applied into `dir`.
+fn patch_civetweb(dir: &PathBuf) {
+    let mut source = String::new();
+    File::open("civetweb/src/civetweb.c")
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .unwrap();
+    for &(from, to) in PATCHES {
+        if source.matches(from).count() != 1 {
+            panic!("can't patch civetweb.c: `{}` isn't there exactly once",
+                   from.trim());
+        }
+        source = source.replacen(from, to, 1);
+    }
+    File::create(dir.join("civetweb.c"))
+        .and_then(|mut f| f.write_all(source.as_bytes()))
+        .unwrap();
+}
 
 fn main() {
-    let mut dst = Config::new("civetweb")
-                         .define("CMAKE_BUILD_TYPE", "Release")
-                         .define("BUILD_TESTING", "OFF")
-                         .define("CIVETWEB_ALLOW_WARNINGS", "ON")
-                         .build();
-    dst.push("lib");
-    println!("cargo:rustc-link-search=native={}", dst.display());
-    println!("cargo:rustc-link-lib=static=civetweb");
+    check_civetweb();
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    patch_civetweb(&out_dir);
+    let link_openssl = env::var_os("CARGO_FEATURE_LINK_OPENSSL").is_some();
+    let mut build = cc::Build::new();
+    // The settings civetweb's own CMake build uses by default, except that
+    // worker threads keep the system's stack size rather than 100 KiB,
+    // which handlers written in Rust can easily run out of.
+    build.file("civet.c")
+         .include(&out_dir)
+         .include("civetweb/src")
+         .include("civetweb/include")
+         .define("NDEBUG", None)
+         .define("MAX_REQUEST_SIZE", "16384")
+         .warnings(false);
+    if link_openssl {
+        build.define("NO_SSL_DL", None);
+    }
+    build.compile("civetweb");
+
+    println!("cargo:rerun-if-changed=civet.c");
+    println!("cargo:rerun-if-changed=civetweb/src");
+    println!("cargo:rerun-if-changed=civetweb/include");
+    if link_openssl {
+        println!("cargo:rustc-link-lib=ssl");
+        println!("cargo:rustc-link-lib=crypto");
+    }
+    match &env::var("CARGO_CFG_TARGET_OS").unwrap()[..] {
+        "windows" => println!("cargo:rustc-link-lib=ws2_32"),
+        os => {
+            println!("cargo:rustc-link-lib=pthread");
+            println!("cargo:rustc-link-lib=dl");
+            if os == "linux" {
+                println!("cargo:rustc-link-lib=rt");
+            }
+        }
+    }
 }
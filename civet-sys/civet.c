@@ -0,0 +1,241 @@
+/* civetweb, plus accessors for the parts of its private `struct
+ * mg_connection` that civet needs. They are compiled in the same unit as
+ * civetweb.c so that the compiler, not a copy of the layout on the Rust
+ * side, knows where the fields are. build.rs patches the civetweb.c
+ * included here to use the definitions that precede it. */
+
+/* The most accepted connections civetweb can queue for its worker threads;
+ * civet_set_connection_queue picks how many of those it does queue. */
+#define MGSQLEN (1024)
+
+/* How many connections are queued when civet_set_connection_queue hasn't
+ * been called: civetweb's own MGSQLEN. */
+#define CIVET_DEFAULT_CONNECTION_QUEUE (20)
+
+/* Added to the start of `struct mg_context`. */
+#define CIVET_CONTEXT_FIELDS                                                   \
+	int civet_queue_limit;                                                     \
+	const char *civet_queue_reply;                                             \
+	void (*civet_queue_rejected)(void *user_data);
+
+/* Added to the start of `struct mg_connection`. */
+#define CIVET_CONNECTION_FIELDS char *civet_request_target;
+
+#include <stddef.h>
+
+struct mg_context;
+struct mg_connection;
+struct mg_form_data_handler;
+struct socket;
+
+/* Called by accept_new_connection in place of produce_socket. */
+static void civet_produce_socket(struct mg_context *ctx,
+                                 const struct socket *sp);
+
+/* Called by handle_request before it touches the request target, and by
+ * close_connection. */
+static void civet_save_request_target(struct mg_connection *conn);
+static void civet_free_request_target(struct mg_connection *conn);
+
+/* Called by mg_handle_form_request in place of mg_read. */
+static int civet_form_read(struct mg_form_data_handler *fdh,
+                           struct mg_connection *conn,
+                           void *buf,
+                           size_t len);
+
+#include "civetweb.c"
+
+/* The connection's OpenSSL `SSL *`, NULL unless it is a TLS connection. */
+void *
+civet_get_ssl(const struct mg_connection *conn)
+{
+#if !defined(NO_SSL)
+	return conn->ssl;
+#else
+	(void)conn;
+	return NULL;
+#endif
+}
+
+/* The connection's socket. */
+int
+civet_get_socket(const struct mg_connection *conn)
+{
+	return (int)conn->client.sock;
+}
+
+/* Makes civetweb close the connection once the current request is done,
+ * rather than read another request from it. */
+void
+civet_set_must_close(struct mg_connection *conn)
+{
+	conn->must_close = 1;
+}
+
+/* The request target as the client sent it. handle_request splits off the
+ * query string, decodes and removes dot segments from the one in
+ * request_info in place. NULL outside a request handler. */
+const char *
+civet_request_target(const struct mg_connection *conn)
+{
+	return conn->civet_request_target;
+}
+
+static void
+civet_save_request_target(struct mg_connection *conn)
+{
+	civet_free_request_target(conn);
+	if (conn->request_info.request_uri != NULL) {
+		conn->civet_request_target = mg_strdup(conn->request_info.request_uri);
+	}
+}
+
+static void
+civet_free_request_target(struct mg_connection *conn)
+{
+	mg_free(conn->civet_request_target);
+	conn->civet_request_target = NULL;
+}
+
+/* Reads the trailer section that follows the last chunk of a chunked
+ * request body into buf, up to and including the empty line that ends it;
+ * mg_read stops short of it. Call it once mg_read has returned 0. Returns
+ * the section's length, 0 if the body is not chunked or not read to its
+ * end, or -1 if the section is malformed or does not fit in len bytes. */
+int
+civet_read_trailers(struct mg_connection *conn, char *buf, size_t len)
+{
+	size_t n = 0;
+
+	if (conn->is_chunked != 2) {
+		return 0;
+	}
+	while (n < len) {
+		char c = mg_getc(conn);
+		if (c == '\0') {
+			return -1;
+		}
+		buf[n++] = c;
+		if ((c == '\n') && (n >= 2) && (buf[n - 2] == '\r')
+		    && ((n == 2) || ((n >= 4) && (buf[n - 4] == '\r')
+		                     && (buf[n - 3] == '\n')))) {
+			return (int)n;
+		}
+	}
+	return -1;
+}
+
+/* Sets the backlog of the context's listening sockets, which civetweb
+ * always starts with SOMAXCONN, by listening on them again. Returns 0, or
+ * -1 with errno set if that fails for any of them. */
+int
+civet_set_listen_backlog(struct mg_context *ctx, int backlog)
+{
+	unsigned int i;
+
+	for (i = 0; i < ctx->num_listening_sockets; i++) {
+		if (listen(ctx->listening_sockets[i].sock, backlog) != 0) {
+			return -1;
+		}
+	}
+	return 0;
+}
+
+/* Queues at most `limit` accepted connections (up to MGSQLEN) for the worker
+ * threads. When the queue is full, the master thread waits for a worker to
+ * take a connection off it, unless `rejected` is given: then connections
+ * that arrive are counted by calling `rejected` with the context's user
+ * data, then sent `reply`, unless they are TLS connections, and closed. `reply` must
+ * outlive the context. Returns 0, or -1 if `limit` is out of range. */
+int
+civet_set_connection_queue(struct mg_context *ctx,
+                           int limit,
+                           const char *reply,
+                           void (*rejected)(void *user_data))
+{
+	if ((limit < 1) || (limit > MGSQLEN)) {
+		return -1;
+	}
+	(void)pthread_mutex_lock(&ctx->thread_mutex);
+	ctx->civet_queue_limit = limit;
+	ctx->civet_queue_reply = reply;
+	ctx->civet_queue_rejected = rejected;
+	(void)pthread_cond_signal(&ctx->sq_empty);
+	(void)pthread_mutex_unlock(&ctx->thread_mutex);
+	return 0;
+}
+
+static int
+civet_queue_full(const struct mg_context *ctx)
+{
+	int limit = (ctx->civet_queue_limit > 0) ? ctx->civet_queue_limit
+	                                         : CIVET_DEFAULT_CONNECTION_QUEUE;
+	return (ctx->sq_head - ctx->sq_tail) >= limit;
+}
+
+static void
+civet_produce_socket(struct mg_context *ctx, const struct socket *sp)
+{
+	char buf[1024];
+	int full;
+
+	(void)pthread_mutex_lock(&ctx->thread_mutex);
+	while ((ctx->stop_flag == 0) && (ctx->civet_queue_rejected == NULL)
+	       && civet_queue_full(ctx)) {
+		(void)pthread_cond_wait(&ctx->sq_empty, &ctx->thread_mutex);
+	}
+	full = (ctx->stop_flag == 0) && civet_queue_full(ctx);
+	(void)pthread_mutex_unlock(&ctx->thread_mutex);
+
+	if (!full) {
+		produce_socket(ctx, sp);
+		return;
+	}
+	ctx->civet_queue_rejected(ctx->user_data);
+
+	/* The socket is new and non-blocking, so a short reply fits in its send
+	 * buffer. Shut down our side and read what the client has sent so far,
+	 * so that closing doesn't reset the connection before the client has
+	 * read the reply; the master thread can't wait for any more. */
+	if (!sp->is_ssl && (ctx->civet_queue_reply != NULL)) {
+		(void)send(sp->sock,
+		           ctx->civet_queue_reply,
+		           (int)strlen(ctx->civet_queue_reply),
+		           MSG_NOSIGNAL);
+	}
+	(void)shutdown(sp->sock, SHUTDOWN_WR);
+	while (recv(sp->sock, buf, sizeof(buf), 0) > 0) {
+	}
+	closesocket(sp->sock);
+}
+
+/* A form data handler with the reader mg_handle_form_request uses for the
+ * request body. `read` is called with the handler's `user_data` and returns
+ * what mg_read would. */
+struct civet_form_data_handler {
+	struct mg_form_data_handler handler;
+	int (*read)(void *user_data, void *buf, size_t len);
+};
+
+/* mg_handle_form_request, reading the body with `fdh->read`. civet only
+ * calls mg_handle_form_request through here, so the handler it is given is
+ * always the first member of a civet_form_data_handler. */
+int
+civet_handle_form_request(struct mg_connection *conn,
+                          struct civet_form_data_handler *fdh)
+{
+	return mg_handle_form_request(conn, &fdh->handler);
+}
+
+static int
+civet_form_read(struct mg_form_data_handler *fdh,
+                struct mg_connection *conn,
+                void *buf,
+                size_t len)
+{
+	struct civet_form_data_handler *civet =
+	    (struct civet_form_data_handler *)fdh;
+
+	(void)conn;
+	return civet->read(fdh->user_data, buf, len);
+}
@@ -0,0 +1,395 @@
+//! HTTP authentication.
+//!
+//! `Basic` and `Digest` wrap a handler and only pass it requests with valid
+//! credentials, answering the rest with a 401 challenge. The handler finds
+//! the user in `CivetRequestExt::remote_user`.
+//!
+//! Users come from a `UserStore`. `PasswordFile` is one backed by the
+//! `.htpasswd` files civetweb itself uses, with `user:realm:ha1` lines.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use conduit::{Handler, Request, Response};
+
+use proxy::{split_quoted, unquote};
+use random;
+use raw;
+use request::CivetRequestExt;
+use status;
+
+/// How long a `Digest` nonce is good for, in seconds. Clients asked to
+/// authenticate with an expired one retry with a new one without bothering
+/// the user.
+const NONCE_LIFETIME: u64 = 300;
+
+/// Where `Basic` and `Digest` look users up.
+pub trait UserStore: Send + Sync + 'static {
+    /// The `ha1` of `user`'s password in `realm`, or `None` if there is no
+    /// such user.
+    fn ha1(&self, user: &str, realm: &str) -> Option<String>;
+}
+
+/// What a `UserStore` keeps instead of a password: the MD5 of
+/// `user:realm:password`, in lowercase hex.
+///
+/// # Panics
+///
+/// If any of the arguments contain a NUL byte.
+pub fn ha1(user: &str, realm: &str, password: &str) -> String {
+    raw::md5(&format!("{}:{}:{}", user, realm, password))
+        .expect("NUL byte in credentials")
+}
+
+/// An `.htpasswd` file in civetweb's format. It is read on every lookup, so
+/// changes to it take effect straight away.
+pub struct PasswordFile {
+    path: PathBuf,
+}
+
+impl PasswordFile {
+    pub fn new(path: &Path) -> PasswordFile {
+        PasswordFile { path: path.to_path_buf() }
+    }
+
+    /// Adds `user` to `realm`, or changes their password. The file is
+    /// created if it doesn't exist.
+    pub fn set_password(&self, realm: &str, user: &str,
+                        password: &str) -> io::Result<()> {
+        // civetweb takes an empty password to mean "remove the user".
+        if password.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "passwords can't be empty"))
+        }
+        raw::modify_passwords_file(try!(self.path_str()), realm, user,
+                                   Some(password))
+    }
+
+    /// Removes `user` from `realm`.
+    pub fn remove_user(&self, realm: &str, user: &str) -> io::Result<()> {
+        raw::modify_passwords_file(try!(self.path_str()), realm, user, None)
+    }
+
+    fn path_str(&self) -> io::Result<&str> {
+        self.path.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "password file paths must be UTF-8")
+        })
+    }
+}
+
+impl UserStore for PasswordFile {
+    fn ha1(&self, user: &str, realm: &str) -> Option<String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("failed to open {}: {}", self.path.display(), e);
+                return None
+            }
+        };
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(..) => break,
+            };
+            let mut parts = line.splitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(u), Some(r), Some(ha1)) if u == user && r == realm => {
+                    return Some(ha1.trim().to_string())
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Basic authentication. Passwords go over the wire as they are, so this
+/// should only be used over HTTPS.
+pub struct Basic<H> {
+    realm: String,
+    users: Box<UserStore>,
+    handler: H,
+}
+
+impl<H: Handler> Basic<H> {
+    pub fn new<U: UserStore>(realm: &str, users: U, handler: H) -> Basic<H> {
+        Basic {
+            realm: realm.to_string(),
+            users: Box::new(users),
+            handler: handler,
+        }
+    }
+
+    fn check(&self, req: &Request) -> Option<String> {
+        let credentials = match credentials(req, "Basic")
+            .and_then(|c| base64::decode(c).ok())
+            .and_then(|c| String::from_utf8(c).ok()) {
+            Some(credentials) => credentials,
+            None => return None,
+        };
+        if credentials.contains('\0') {
+            return None
+        }
+        let colon = match credentials.find(':') {
+            Some(colon) => colon,
+            None => return None,
+        };
+        let (user, password) = (&credentials[..colon], &credentials[colon + 1..]);
+        let expected = match self.users.ha1(user, &self.realm) {
+            Some(expected) => expected,
+            None => return None,
+        };
+        if same(&ha1(user, &self.realm, password), &expected.to_lowercase()) {
+            Some(user.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+impl<H: Handler> Handler for Basic<H> {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        match self.check(req) {
+            Some(user) => {
+                req.mut_extensions().insert(::RemoteUser(user));
+                self.handler.call(req)
+            }
+            None => {
+                Ok(challenge(format!("Basic realm={}, charset=\"UTF-8\"",
+                                     quote(&self.realm))))
+            }
+        }
+    }
+}
+
+/// Digest authentication (RFC 7616) with MD5, as browsers and civetweb
+/// support it. Passwords never go over the wire, but the requests
+/// themselves aren't protected, so HTTPS is still a good idea.
+///
+/// civetweb only exports `mg_md5`, which all the hashing goes through. Its
+/// own digest check is tied to the password files it opens itself, so the
+/// check and nonces are done here.
+pub struct Digest<H> {
+    realm: String,
+    users: Box<UserStore>,
+    handler: H,
+    secret: String,
+    /// The nonces that have been used and haven't expired, with when they
+    /// were issued and the highest `nc` they have been used with.
+    used: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+enum Outcome {
+    User(String),
+    Stale,
+    Denied,
+}
+
+impl<H: Handler> Digest<H> {
+    /// Nonces are good for five minutes. Each request made with one has to
+    /// count up its `nc`, so a captured `Authorization` header can't be
+    /// sent again. A client that sends requests out of order, or that
+    /// doesn't send `qop=auth`, is asked to retry with a new nonce.
+    ///
+    /// # Panics
+    ///
+    /// If the operating system's random source, which the nonces are keyed
+    /// with, can't be read.
+    pub fn new<U: UserStore>(realm: &str, users: U, handler: H) -> Digest<H> {
+        Digest {
+            realm: realm.to_string(),
+            users: Box::new(users),
+            handler: handler,
+            secret: random::hex(16).expect("failed to read random bytes"),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A nonce that can be checked later without keeping track of it.
+    fn nonce(&self, time: u64) -> String {
+        let tag = raw::md5(&format!("{}:{:x}", self.secret, time)).unwrap();
+        format!("{:x}.{}", time, tag)
+    }
+
+    /// When `nonce` was issued, if it was issued by us.
+    fn nonce_time(&self, nonce: &str) -> Option<u64> {
+        let time = match nonce.find('.') {
+            Some(dot) => u64::from_str_radix(&nonce[..dot], 16).ok(),
+            None => None,
+        };
+        time.filter(|&time| same(&self.nonce(time), nonce))
+    }
+
+    /// Records a request made with `nonce`, issued at `time`, and counted
+    /// `nc` by the client. False if the nonce has been used with that count
+    /// or a higher one already.
+    fn count(&self, nonce: &str, time: u64, nc: u64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let now = now();
+        used.retain(|_, &mut (time, _)| now.saturating_sub(time) <= NONCE_LIFETIME);
+        match used.get(nonce) {
+            Some(&(_, last)) if last >= nc => return false,
+            _ => {}
+        }
+        used.insert(nonce.to_string(), (time, nc));
+        true
+    }
+
+    fn check(&self, req: &Request) -> Outcome {
+        let params = match credentials(req, "Digest") {
+            Some(credentials) => parse_params(credentials),
+            None => return Outcome::Denied,
+        };
+        let param = |name: &str| params.get(name).map(|s| &s[..]);
+        let (user, nonce, uri, response) = match (param("username"),
+                                                   param("nonce"), param("uri"),
+                                                   param("response")) {
+            (Some(u), Some(n), Some(uri), Some(r)) => (u, n, uri, r),
+            _ => return Outcome::Denied,
+        };
+        if param("realm") != Some(&self.realm[..]) ||
+           !is_target(req, uri) ||
+           params.values().any(|v| v.contains('\0')) {
+            return Outcome::Denied
+        }
+        match param("algorithm") {
+            Some(algorithm) if !algorithm.eq_ignore_ascii_case("MD5") => {
+                return Outcome::Denied
+            }
+            _ => {}
+        }
+        let time = match self.nonce_time(nonce) {
+            Some(time) => time,
+            None => return Outcome::Denied,
+        };
+
+        let ha1 = match self.users.ha1(user, &self.realm) {
+            Some(ha1) => ha1.to_lowercase(),
+            None => return Outcome::Denied,
+        };
+        let ha2 = raw::md5(&format!("{}:{}", req.method(), uri)).unwrap();
+        // Without `qop`, there is no count, so the nonce is good for one
+        // request.
+        let (expected, count) = match (param("qop"), param("nc"), param("cnonce")) {
+            (Some("auth"), Some(nc), Some(cnonce)) => {
+                (format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2),
+                 u64::from_str_radix(nc, 16).ok())
+            }
+            (None, None, None) => (format!("{}:{}:{}", ha1, nonce, ha2), Some(1)),
+            _ => return Outcome::Denied,
+        };
+        let count = match count {
+            Some(count) if count > 0 => count,
+            _ => return Outcome::Denied,
+        };
+        if !same(&raw::md5(&expected).unwrap(), &response.to_lowercase()) {
+            Outcome::Denied
+        } else if now().saturating_sub(time) > NONCE_LIFETIME ||
+                  !self.count(nonce, time, count) {
+            Outcome::Stale
+        } else {
+            Outcome::User(user.to_string())
+        }
+    }
+}
+
+impl<H: Handler> Handler for Digest<H> {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let stale = match self.check(req) {
+            Outcome::User(user) => {
+                req.mut_extensions().insert(::RemoteUser(user));
+                return self.handler.call(req)
+            }
+            Outcome::Stale => ", stale=true",
+            Outcome::Denied => "",
+        };
+        Ok(challenge(format!("Digest realm={}, qop=\"auth\", nonce=\"{}\", \
+                              algorithm=MD5{}",
+                             quote(&self.realm), self.nonce(now()), stale)))
+    }
+}
+
+/// The credentials in the request's `Authorization` header, if it uses
+/// `scheme`.
+fn credentials<'a>(req: &'a Request, scheme: &str) -> Option<&'a str> {
+    let header = match req.headers().find("Authorization")
+        .and_then(|values| values.into_iter().next()) {
+        Some(header) => header.trim(),
+        None => return None,
+    };
+    let space = header.find(' ').unwrap_or(header.len());
+    if header[..space].eq_ignore_ascii_case(scheme) {
+        Some(header[space..].trim())
+    } else {
+        None
+    }
+}
+
+/// Parses the `name=value` pairs of `Digest` credentials. Names are
+/// lowercased and values unquoted.
+fn parse_params(s: &str) -> HashMap<String, String> {
+    split_quoted(s, ',').iter().filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => {
+                Some((name.trim().to_ascii_lowercase(), unquote(value.trim())))
+            }
+            _ => None,
+        }
+    }).collect()
+}
+
+/// Whether `uri`, from `Digest` credentials, is the target of `req`.
+fn is_target(req: &Request, uri: &str) -> bool {
+    if let Some(target) = req.request_uri() {
+        return target == uri
+    }
+    // civetweb decoded the target, so compare it decoded.
+    let mut parts = uri.splitn(2, '?');
+    let path = parts.next().unwrap();
+    ::decode_path(path) == req.path() && parts.next() == req.query_string()
+}
+
+fn challenge(authenticate: String) -> Response {
+    let mut headers = HashMap::new();
+    headers.insert("WWW-Authenticate".to_string(), vec![authenticate]);
+    headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+    ::response(status::Unauthorized, headers, io::empty())
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Compares without giving away how much of `a` and `b` matched.
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() &&
+        a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_params;
+
+    #[test]
+    fn digest_params() {
+        let params = parse_params("username=\"Mufasa\", realm=\"a, \\\"b\\\"\", \
+                                   nc=00000001, QOP=auth");
+        assert_eq!(params["username"], "Mufasa");
+        assert_eq!(params["realm"], "a, \"b\"");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+    }
+}
@@ -0,0 +1,501 @@
+//! Compressing responses.
+//!
+//! `Compress` wraps a handler and compresses what it returns with brotli,
+//! gzip or deflate, whichever the client's `Accept-Encoding` prefers. The
+//! body is compressed as it is written, so nothing is buffered; since the
+//! compressed length isn't known up front, HTTP/1.1 responses are sent with
+//! `Transfer-Encoding: chunked` and HTTP/1.0 ones end when the connection
+//! closes. Responses the handler already gave a `Transfer-Encoding` write
+//! their own framing into the body, so those are passed on uncompressed.
+//!
+//! `Precompressed` serves static files, and for those it doesn't compress at
+//! all: it sends the `.br` or `.gz` file next to the one asked for, when
+//! there is one the client accepts. It answers `Range` requests too.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use brotli::CompressorWriter;
+use conduit::{Handler, Method, Request, Response, WriteBody};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use date;
+use range;
+use raw;
+
+/// The content types compressed by default. Entries ending in `/` match
+/// every subtype.
+const CONTENT_TYPES: [&'static str; 9] = [
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// Smaller bodies aren't worth the trouble.
+const MIN_SIZE: u64 = 1024;
+
+/// The precompressed files `Precompressed` looks for, by extension.
+const PRECOMPRESSED: [(Encoding, &'static str); 2] = [(Encoding::Brotli, "br"),
+                                                       (Encoding::Gzip, "gz")];
+
+/// Fast enough to do for every response, unlike the default of 11.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+/// What we can compress with, in the order we prefer them when the client
+/// doesn't care.
+const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+pub struct Compress<H> {
+    handler: H,
+    min_size: u64,
+    content_types: Vec<String>,
+}
+
+impl<H: Handler> Compress<H> {
+    /// Compresses `handler`'s text, JavaScript, JSON, XML, SVG and
+    /// WebAssembly responses of 1 KiB or more. Responses without a
+    /// `Content-Length` are compressed whatever their size.
+    pub fn new(handler: H) -> Compress<H> {
+        Compress {
+            handler: handler,
+            min_size: MIN_SIZE,
+            content_types: CONTENT_TYPES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Leaves responses with a `Content-Length` under `bytes` alone.
+    pub fn min_size(&mut self, bytes: u64) -> &mut Compress<H> {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Compresses responses of `content_type` too, or of any of its
+    /// subtypes if it ends in `/`.
+    pub fn content_type(&mut self, content_type: &str) -> &mut Compress<H> {
+        self.content_types.push(content_type.to_ascii_lowercase());
+        self
+    }
+
+    /// Whether `response` could be compressed, depending on what the
+    /// client accepts.
+    fn compressible(&self, response: &Response) -> bool {
+        let headers = &response.headers;
+        match response.status.0 {
+            status if status < 200 => return false,
+            204 | 206 | 304 => return false,
+            _ => {}
+        }
+        if ::response_header(headers, "Content-Encoding").is_some() ||
+           ::response_header(headers, "Transfer-Encoding").is_some() ||
+           ::response_header(headers, "Content-Range").is_some() {
+            return false
+        }
+        if ::response_header(headers, "Cache-Control").map_or(false, |value| {
+            value.split(',').any(|d| d.trim().eq_ignore_ascii_case("no-transform"))
+        }) {
+            return false
+        }
+        let length = ::response_header(headers, "Content-Length")
+            .and_then(|s| s.trim().parse().ok());
+        if length.map_or(false, |length: u64| length < self.min_size) {
+            return false
+        }
+        let content_type = match ::response_header(headers, "Content-Type") {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        let media_type = content_type.split(';').next().unwrap().trim().to_ascii_lowercase();
+        self.content_types.iter().any(|t| {
+            if t.ends_with('/') {
+                media_type.starts_with(&t[..])
+            } else {
+                media_type == *t
+            }
+        })
+    }
+}
+
+impl<H: Handler> Handler for Compress<H> {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let mut response = try!(self.handler.call(req));
+        if !self.compressible(&response) {
+            return Ok(response)
+        }
+        vary(&mut response.headers);
+        let accept = accept_encoding(req);
+        let encoding = match accept.and_then(|accept| negotiate(&accept, &ENCODINGS)) {
+            Some(encoding) => encoding,
+            None => return Ok(response),
+        };
+
+        let chunked = req.http_version() >= ::ver(1, 1);
+        {
+            let headers = &mut response.headers;
+            ::remove_response_header(headers, "Content-Length");
+            // Ranges would be of the compressed bytes, which we can't seek in.
+            ::remove_response_header(headers, "Accept-Ranges");
+            headers.insert("Content-Encoding".to_string(),
+                           vec![encoding.name().to_string()]);
+            if chunked {
+                headers.insert("Transfer-Encoding".to_string(),
+                               vec!["chunked".to_string()]);
+            }
+            // The compressed body isn't byte for byte what the tag was for.
+            for (name, values) in headers.iter_mut() {
+                if name.eq_ignore_ascii_case("ETag") {
+                    for value in values.iter_mut().filter(|v| v.starts_with('"')) {
+                        value.insert_str(0, "W/");
+                    }
+                }
+            }
+        }
+        if !chunked {
+            if let Ok(conn) = ::raw_connection(req) {
+                raw::force_close(&conn);
+            }
+        }
+        let body = mem::replace(&mut response.body, Box::new(io::empty()));
+        if req.method() != Method::Head {
+            response.body = Box::new(Compressed {
+                body: body,
+                encoding: encoding,
+                chunked: chunked,
+            });
+        }
+        Ok(response)
+    }
+}
+
+/// A body compressed as it is written.
+struct Compressed {
+    body: Box<WriteBody + Send>,
+    encoding: Encoding,
+    chunked: bool,
+}
+
+impl Compressed {
+    fn compress(&mut self, out: &mut Write) -> io::Result<u64> {
+        match self.encoding {
+            Encoding::Brotli => {
+                let mut encoder = CompressorWriter::new(Checked::new(out), 4096,
+                                                        BROTLI_QUALITY,
+                                                        BROTLI_WINDOW);
+                let n = try!(self.body.write_body(&mut encoder));
+                // Finishing the stream is left to `into_inner`, which drops
+                // any error writing out the end of it.
+                match encoder.into_inner().error {
+                    Some(err) => Err(err),
+                    None => Ok(n),
+                }
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(out, Compression::default());
+                let n = try!(self.body.write_body(&mut encoder));
+                try!(encoder.finish());
+                Ok(n)
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(out, Compression::default());
+                let n = try!(self.body.write_body(&mut encoder));
+                try!(encoder.finish());
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl WriteBody for Compressed {
+    fn write_body(&mut self, out: &mut Write) -> io::Result<u64> {
+        if !self.chunked {
+            return self.compress(out)
+        }
+        let n = try!(self.compress(&mut Chunked(&mut *out)));
+        try!(out.write_all(b"0\r\n\r\n"));
+        Ok(n)
+    }
+}
+
+/// Keeps the first error writing to `W`.
+struct Checked<W> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Checked<W> {
+    fn new(inner: W) -> Checked<W> {
+        Checked { inner: inner, error: None }
+    }
+
+    fn check<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        result.map_err(|err| {
+            let ret = io::Error::new(err.kind(), err.to_string());
+            if self.error.is_none() {
+                self.error = Some(err);
+            }
+            ret
+        })
+    }
+}
+
+impl<W: Write> Write for Checked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = match self.inner.write(buf) {
+            Ok(0) if !buf.is_empty() => {
+                Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write body"))
+            }
+            result => result,
+        };
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.check(result)
+    }
+}
+
+/// Writes each write as one chunk of a chunked body.
+struct Chunked<'a>(&'a mut Write);
+
+impl<'a> Write for Chunked<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body.
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        try!(write!(self.0, "{:X}\r\n", buf.len()));
+        try!(self.0.write_all(buf));
+        try!(self.0.write_all(b"\r\n"));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+pub struct Precompressed {
+    root: PathBuf,
+}
+
+impl Precompressed {
+    /// Serves the files under `root`. A request for `/app.js` gets
+    /// `root/app.js.br` or `root/app.js.gz`, if it exists and the client
+    /// accepts that encoding, and `root/app.js` otherwise.
+    pub fn new<P: AsRef<Path>>(root: P) -> Precompressed {
+        Precompressed { root: root.as_ref().to_path_buf() }
+    }
+
+    /// Where the file for `path` would be, if `path` doesn't try to leave
+    /// `root`.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => return None,
+                _ if part.contains('\\') || part.contains('\0') => return None,
+                _ => file.push(part),
+            }
+        }
+        Some(file)
+    }
+}
+
+impl Handler for Precompressed {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let mut headers = HashMap::new();
+        match req.method() {
+            Method::Get | Method::Head => {}
+            _ => {
+                headers.insert("Allow".to_string(), vec!["GET, HEAD".to_string()]);
+                headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+                return Ok(::response(405, headers, io::empty()))
+            }
+        }
+        let path = match self.resolve(req.path()) {
+            Some(path) => path,
+            None => return Ok(not_found()),
+        };
+
+        let variants = PRECOMPRESSED.iter().filter(|&&(_, extension)| {
+            is_file(&sibling(&path, extension))
+        }).cloned().collect::<Vec<_>>();
+        let offered = variants.iter().map(|&(encoding, _)| encoding).collect::<Vec<_>>();
+        let encoding = accept_encoding(req).and_then(|accept| negotiate(&accept, &offered));
+        let file = match variants.iter().find(|&&(e, _)| Some(e) == encoding) {
+            Some(&(_, extension)) => sibling(&path, extension),
+            None if is_file(&path) => path.clone(),
+            None => return Ok(not_found()),
+        };
+        let (body, modified) = match File::open(&file).and_then(|f| {
+            f.metadata().map(|m| (f, m.modified().ok()))
+        }) {
+            Ok(pair) => pair,
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        headers.insert("Content-Type".to_string(),
+                       vec![content_type(&path).to_string()]);
+        if let Some(modified) = modified {
+            headers.insert("Last-Modified".to_string(), vec![date::format(modified)]);
+        }
+        if let Some(encoding) = encoding {
+            headers.insert("Content-Encoding".to_string(),
+                           vec![encoding.name().to_string()]);
+        }
+        if !variants.is_empty() {
+            vary(&mut headers);
+        }
+        range::serve(req, headers, body).map_err(|e| Box::new(e) as Box<Error+Send>)
+    }
+}
+
+fn not_found() -> Response {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+    ::response(404, headers, io::empty())
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+fn is_file(path: &Path) -> bool {
+    fs::metadata(path).map(|m| m.is_file()).unwrap_or(false)
+}
+
+/// A content type for the file at `path`, going by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    match &extension[..] {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+fn accept_encoding(req: &Request) -> Option<String> {
+    req.headers().find("Accept-Encoding").map(|values| values.join(","))
+}
+
+/// Picks the encoding from `offered` that the `Accept-Encoding` header
+/// `accept` gives the highest weight, preferring those earlier in
+/// `offered` on ties.
+fn negotiate(accept: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let mut weights = vec![None; offered.len()];
+    let mut wildcard = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap().trim();
+        let q = params.filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("q") => {
+                    value.trim().parse::<f32>().ok()
+                }
+                _ => None,
+            }
+        }).next().unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+            continue
+        }
+        let name = if name.eq_ignore_ascii_case("x-gzip") { "gzip" } else { name };
+        if let Some(i) = offered.iter().position(|e| name.eq_ignore_ascii_case(e.name())) {
+            weights[i] = Some(q);
+        }
+    }
+    let mut best: Option<(Encoding, f32)> = None;
+    for (&encoding, weight) in offered.iter().zip(weights) {
+        let q = weight.or(wildcard).unwrap_or(0.0);
+        if q > 0.0 && best.map_or(true, |(_, best)| q > best) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Adds `Accept-Encoding` to the `Vary` header.
+fn vary(headers: &mut HashMap<String, Vec<String>>) {
+    let key = headers.keys()
+        .find(|key| key.eq_ignore_ascii_case("Vary"))
+        .cloned()
+        .unwrap_or_else(|| "Vary".to_string());
+    let values = headers.entry(key).or_insert_with(Vec::new);
+    let varies = values.iter().flat_map(|v| v.split(',')).any(|v| {
+        let v = v.trim();
+        v == "*" || v.eq_ignore_ascii_case("Accept-Encoding")
+    });
+    if !varies {
+        values.push("Accept-Encoding".to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, Encoding, ENCODINGS};
+
+    #[test]
+    fn negotiates() {
+        let all = &ENCODINGS[..];
+        assert_eq!(negotiate("gzip, deflate, br", all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, deflate", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate;q=1, gzip;q=0.5", all), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br;q=0, *", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.1, deflate;q=0.2", all), Some(Encoding::Deflate));
+        assert_eq!(negotiate("x-gzip", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("GZIP;Q=0.5", all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity", all), None);
+        assert_eq!(negotiate("gzip;q=0, *;q=0", all), None);
+        assert_eq!(negotiate("", all), None);
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+    }
+}
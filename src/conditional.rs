@@ -0,0 +1,238 @@
+//! Conditional requests (RFC 7232).
+//!
+//! `Conditional` wraps a handler and answers `GET` and `HEAD` requests with
+//! a `304 Not Modified` or `412 Precondition Failed` when the `ETag` and
+//! `Last-Modified` headers on the handler's response say the client's
+//! conditions call for one.
+//!
+//! A wrapper only sees the response after the handler has run, which is too
+//! late for the other methods: an `If-Match` on a `PUT` has to be checked
+//! before anything changes. Handlers do that by calling `check` with the
+//! validators of the resource as it is before the change.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use conduit::{Handler, Method, Request, Response};
+
+use date;
+
+/// The headers describing a body, which a `304` leaves out of the response
+/// it replaces (RFC 7232, section 4.1).
+const NOT_MODIFIED_DROPS: [&'static str; 6] = [
+    "Content-Length",
+    "Content-Type",
+    "Content-Encoding",
+    "Content-Language",
+    "Content-Range",
+    "Transfer-Encoding",
+];
+
+pub struct Conditional<H> {
+    handler: H,
+}
+
+impl<H: Handler> Conditional<H> {
+    pub fn new(handler: H) -> Conditional<H> {
+        Conditional { handler: handler }
+    }
+}
+
+impl<H: Handler> Handler for Conditional<H> {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let response = try!(self.handler.call(req));
+        match req.method() {
+            Method::Get | Method::Head => {}
+            _ => return Ok(response),
+        }
+        // Conditions only apply to what would otherwise be a success.
+        if response.status.0 < 200 || response.status.0 >= 300 {
+            return Ok(response)
+        }
+        let outcome = {
+            let etag = ::response_header(&response.headers, "ETag");
+            let last_modified = ::response_header(&response.headers, "Last-Modified")
+                .and_then(date::parse);
+            evaluate(&*req, etag, last_modified)
+        };
+        Ok(match outcome {
+            Outcome::Proceed => response,
+            Outcome::NotModified => not_modified(response),
+            Outcome::PreconditionFailed => precondition_failed(),
+        })
+    }
+}
+
+/// Checks the request's preconditions against the current `etag` and
+/// `last_modified` time of what it targets, before the handler acts on it.
+/// Returns the response to send instead if they fail: a `412`, or a `304`
+/// for a `GET` or `HEAD` whose copy is still fresh.
+pub fn check(req: &Request, etag: Option<&str>, last_modified: Option<SystemTime>)
+             -> Option<Response> {
+    match evaluate(req, etag, last_modified) {
+        Outcome::Proceed => None,
+        Outcome::NotModified => {
+            let mut headers = HashMap::new();
+            if let Some(etag) = etag {
+                headers.insert("ETag".to_string(), vec![etag.to_string()]);
+            }
+            if let Some(last_modified) = last_modified {
+                headers.insert("Last-Modified".to_string(),
+                               vec![date::format(last_modified)]);
+            }
+            Some(::response(304, headers, io::empty()))
+        }
+        Outcome::PreconditionFailed => Some(precondition_failed()),
+    }
+}
+
+enum Outcome {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+/// RFC 7232, section 6, for a resource that exists.
+fn evaluate(req: &Request, etag: Option<&str>, last_modified: Option<SystemTime>)
+            -> Outcome {
+    let headers = req.headers();
+    let find = |name: &str| headers.find(name).map(|values| values.join(","));
+    let etag = etag.and_then(|etag| EntityTag::parse(etag));
+    let last_modified = last_modified.map(seconds);
+
+    if let Some(if_match) = find("If-Match") {
+        let matched = matches(&if_match, |tag| {
+            etag.as_ref().map_or(false, |etag| etag.strong_eq(tag))
+        });
+        if !matched {
+            return Outcome::PreconditionFailed
+        }
+    } else if let Some(since) = find("If-Unmodified-Since").and_then(|s| date::parse(&s)) {
+        if last_modified.map_or(false, |modified| modified > seconds(since)) {
+            return Outcome::PreconditionFailed
+        }
+    }
+
+    let safe = match req.method() {
+        Method::Get | Method::Head => true,
+        _ => false,
+    };
+    if let Some(if_none_match) = find("If-None-Match") {
+        let matched = matches(&if_none_match, |tag| {
+            etag.as_ref().map_or(false, |etag| etag.weak_eq(tag))
+        });
+        if matched {
+            return if safe { Outcome::NotModified } else { Outcome::PreconditionFailed }
+        }
+    } else if safe {
+        if let Some(since) = find("If-Modified-Since").and_then(|s| date::parse(&s)) {
+            if last_modified.map_or(false, |modified| modified <= seconds(since)) {
+                return Outcome::NotModified
+            }
+        }
+    }
+    Outcome::Proceed
+}
+
+/// Whether the `If-Match` or `If-None-Match` value `list` is `*`, or has a
+/// tag `matches` accepts. A resource that exists always matches `*`.
+fn matches<F: Fn(&EntityTag) -> bool>(list: &str, matches: F) -> bool {
+    if list.trim() == "*" {
+        return true
+    }
+    EntityTag::parse_list(list).iter().any(matches)
+}
+
+#[derive(Debug, PartialEq)]
+struct EntityTag<'a> {
+    weak: bool,
+    opaque: &'a str,
+}
+
+impl<'a> EntityTag<'a> {
+    /// Parses one tag, like `"xyzzy"` or `W/"xyzzy"`.
+    fn parse(s: &'a str) -> Option<EntityTag<'a>> {
+        let mut tags = EntityTag::parse_list(s);
+        if tags.len() == 1 { tags.pop() } else { None }
+    }
+
+    /// Parses a comma-separated list of tags, skipping anything malformed.
+    /// Commas are allowed inside the quotes, so this can't just split.
+    fn parse_list(mut s: &'a str) -> Vec<EntityTag<'a>> {
+        let mut tags = Vec::new();
+        loop {
+            s = s.trim_matches(|c| c == ' ' || c == '\t' || c == ',');
+            if s.is_empty() {
+                return tags
+            }
+            let weak = s.starts_with("W/");
+            if weak {
+                s = &s[2..];
+            }
+            if !s.starts_with('"') {
+                // Not a tag; skip to the next comma.
+                s = s.find(',').map_or("", |i| &s[i..]);
+                continue
+            }
+            match s[1..].find('"') {
+                Some(end) => {
+                    tags.push(EntityTag { weak: weak, opaque: &s[1..end + 1] });
+                    s = &s[end + 2..];
+                }
+                None => return tags,
+            }
+        }
+    }
+
+    fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.opaque == other.opaque
+    }
+
+    fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.opaque == other.opaque
+    }
+}
+
+/// HTTP dates only go down to the second.
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn not_modified(response: Response) -> Response {
+    let mut headers = response.headers;
+    headers.retain(|key, _| {
+        !NOT_MODIFIED_DROPS.iter().any(|drop| key.eq_ignore_ascii_case(drop))
+    });
+    ::response(304, headers, io::empty())
+}
+
+fn precondition_failed() -> Response {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+    ::response(412, headers, io::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EntityTag;
+
+    #[test]
+    fn parses_entity_tags() {
+        let tag = |weak, opaque| EntityTag { weak: weak, opaque: opaque };
+        assert_eq!(EntityTag::parse("\"xyzzy\""), Some(tag(false, "xyzzy")));
+        assert_eq!(EntityTag::parse("W/\"xyzzy\""), Some(tag(true, "xyzzy")));
+        assert_eq!(EntityTag::parse("xyzzy"), None);
+        assert_eq!(EntityTag::parse("\"a\", \"b\""), None);
+        assert_eq!(EntityTag::parse_list("\"a\", W/\"b\",\"c,d\" , junk, \"\""),
+                   vec![tag(false, "a"), tag(true, "b"), tag(false, "c,d"),
+                        tag(false, "")]);
+        assert_eq!(EntityTag::parse_list("\"unterminated"), vec![]);
+
+        assert!(tag(false, "a").strong_eq(&tag(false, "a")));
+        assert!(!tag(true, "a").strong_eq(&tag(false, "a")));
+        assert!(tag(true, "a").weak_eq(&tag(false, "a")));
+        assert!(!tag(true, "a").weak_eq(&tag(true, "b")));
+    }
+}
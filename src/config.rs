@@ -1,18 +1,105 @@
 use libc::c_char;
 use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+#[cfg(feature = "secure-cookies")]
+use std::sync::Arc;
+
+#[cfg(feature = "secure-cookies")]
+use cookie::Key;
+use proxy::Cidr;
+#[cfg(feature = "tls")]
+use ssl::Verify;
 
 pub struct Config {
     port: Option<u16>,
+    listeners: Vec<(SocketAddr, bool)>,
     threads: Option<u32>,
+    listen_backlog: Option<u32>,
+    connection_queue: Option<u32>,
+    shed_load: Option<Duration>,
     enable_keep_alive: Option<bool>,
+    decode_url: Option<bool>,
+    request_timeout: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    linger_timeout: Option<Duration>,
+    max_request_body: Option<u64>,
+    handler_deadline: Option<Duration>,
+    mounts: Vec<(String, Mount)>,
+    trusted_proxies: Vec<String>,
+    trust_request_id: bool,
+    access_control: Option<AccessControl>,
+    throttle: Option<Throttle>,
+    ssl_certificate: Option<PathBuf>,
+    ssl_verify_peer: Option<bool>,
+    ssl_ca_file: Option<PathBuf>,
+    ssl_ca_path: Option<PathBuf>,
+    ssl_verify_depth: Option<u32>,
+    #[cfg(feature = "tls")]
+    tls_hosts: Vec<(String, PathBuf, PathBuf)>,
+    #[cfg(feature = "secure-cookies")]
+    cookie_key: Option<Arc<Key>>,
+}
+
+/// Settings that apply only to requests whose path falls under a prefix.
+///
+/// Anything left unset on a mount falls back to the server-wide setting on
+/// `Config`.
+pub struct Mount {
+    max_request_body: Option<u64>,
+    handler_deadline: Option<Duration>,
+    access_control: Option<AccessControl>,
+}
+
+/// Which client addresses may use the server, like civetweb's
+/// `access_control_list` but for IPv6 as well.
+///
+/// Rules are checked in order and the last one that matches decides, so
+/// general rules go first. Addresses that no rule matches are denied.
+pub struct AccessControl {
+    rules: Vec<(bool, Cidr)>,
+    invalid: Vec<String>,
+}
+
+/// Bandwidth limits for responses, civetweb's `throttle` option. Each
+/// connection gets the limit of the last rule that matches it; a limit of 0
+/// means no limit.
+pub struct Throttle {
+    rules: Vec<(String, u64)>,
+    invalid: Vec<String>,
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             port: None,
+            listeners: Vec::new(),
             threads: None,
+            listen_backlog: None,
+            connection_queue: None,
+            shed_load: None,
             enable_keep_alive: None,
+            decode_url: None,
+            request_timeout: None,
+            keep_alive_timeout: None,
+            linger_timeout: None,
+            max_request_body: None,
+            handler_deadline: None,
+            mounts: Vec::new(),
+            trusted_proxies: Vec::new(),
+            trust_request_id: false,
+            access_control: None,
+            throttle: None,
+            ssl_certificate: None,
+            ssl_verify_peer: None,
+            ssl_ca_file: None,
+            ssl_ca_path: None,
+            ssl_verify_depth: None,
+            #[cfg(feature = "tls")]
+            tls_hosts: Vec::new(),
+            #[cfg(feature = "secure-cookies")]
+            cookie_key: None,
         }
     }
 
@@ -21,31 +108,477 @@ impl Config {
         self
     }
 
+    /// Listens on `addr` as well as on `port`. Listeners are numbered in the
+    /// order they are added, counting `port` first if it is set; see
+    /// `CivetRequestExt::listener`.
+    pub fn listen(&mut self, addr: SocketAddr) -> &mut Config {
+        self.listeners.push((addr, false));
+        self
+    }
+
+    /// Listens for HTTPS on `addr`, numbered like `listen`. Requires
+    /// `ssl_certificate` or `tls_host`.
+    pub fn listen_tls(&mut self, addr: SocketAddr) -> &mut Config {
+        self.listeners.push((addr, true));
+        self
+    }
+
+    /// A PEM file holding the server's private key and certificate chain.
+    pub fn ssl_certificate(&mut self, path: &Path) -> &mut Config {
+        self.ssl_certificate = Some(path.to_path_buf());
+        self
+    }
+
+    /// Requires HTTPS clients to present a certificate signed by one of the
+    /// authorities in `ssl_ca_file` or `ssl_ca_path`; see
+    /// `CivetRequestExt::client_certificate`.
+    pub fn ssl_verify_peer(&mut self, verify: bool) -> &mut Config {
+        self.ssl_verify_peer = Some(verify);
+        self
+    }
+
+    /// A PEM file of the authorities client certificates are checked against.
+    pub fn ssl_ca_file(&mut self, path: &Path) -> &mut Config {
+        self.ssl_ca_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// A directory of hashed authority certificates, as for OpenSSL's
+    /// `-CApath`.
+    pub fn ssl_ca_path(&mut self, path: &Path) -> &mut Config {
+        self.ssl_ca_path = Some(path.to_path_buf());
+        self
+    }
+
+    /// How many intermediate certificates a client's chain may have.
+    pub fn ssl_verify_depth(&mut self, depth: u32) -> &mut Config {
+        self.ssl_verify_depth = Some(depth);
+        self
+    }
+
+    /// Serves HTTPS clients that ask for `name` through SNI with the
+    /// certificate chain in `cert` and the private key in `key`, both PEM
+    /// files. Clients asking for any other name, or none, get
+    /// `ssl_certificate`. `ssl_verify_peer` applies to both alike.
+    #[cfg(feature = "tls")]
+    pub fn tls_host(&mut self, name: &str, cert: &Path, key: &Path)
+                    -> &mut Config {
+        self.tls_hosts.push((name.to_string(), cert.to_path_buf(),
+                             key.to_path_buf()));
+        self
+    }
+
     pub fn threads(&mut self, threads: u32) -> &mut Config {
         self.threads = Some(threads);
         self
     }
 
+    /// How many connections the OS may queue on each listener before civet
+    /// accepts them. Without it civetweb's compile-time `SOMAXCONN` (100)
+    /// applies. The OS may cap it lower.
+    pub fn listen_backlog(&mut self, backlog: u32) -> &mut Config {
+        self.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// How many accepted connections may wait for one of the `threads` to
+    /// be free, from 1 to 1024. civetweb's default is 20. Once that many
+    /// are waiting, civet stops accepting connections, and they wait in the
+    /// listen backlog instead, unless `shed_load` is on.
+    pub fn connection_queue(&mut self, connections: u32) -> &mut Config {
+        self.connection_queue = Some(connections);
+        self
+    }
+
+    /// Answers connections that arrive while the `connection_queue` is full
+    /// with a 503 and a `Retry-After` of `retry_after`, and closes them,
+    /// rather than leaving them to wait. HTTPS connections are closed
+    /// without an answer, since there's no time for a TLS handshake.
+    pub fn shed_load(&mut self, retry_after: Duration) -> &mut Config {
+        self.shed_load = Some(retry_after);
+        self
+    }
+
     pub fn keep_alive(&mut self, keep_alive: bool) -> &mut Config {
         self.enable_keep_alive = Some(keep_alive);
         self
     }
+
+    /// Whether civetweb percent-decodes the target of a request before it
+    /// reaches the handler, which it does unless told otherwise. `path` is
+    /// decoded either way; turning this off keeps the target as the client
+    /// sent it for `CivetRequestExt::request_uri`, as signature checks need.
+    pub fn decode_url(&mut self, decode: bool) -> &mut Config {
+        self.decode_url = Some(decode);
+        self
+    }
+
+    /// How long civetweb waits for a client to send a request, and for
+    /// each read or write on the connection after that. 30 seconds unless
+    /// set.
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// How long an idle keep-alive connection is kept open for the next
+    /// request. Half a second unless set.
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// How long closing a connection may wait for unsent data to reach the
+    /// client (`SO_LINGER`), rounded up to whole seconds.
+    pub fn linger_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.linger_timeout = Some(timeout);
+        self
+    }
+
+    /// Gives handlers a deadline `after` the request arrives by which to
+    /// produce a response. It is advisory: civet can't stop a running
+    /// handler, so one that never returns keeps its worker thread for good.
+    /// Once the deadline passes, reads of the request body fail and
+    /// whatever the handler returns is replaced by a 504. Handlers can check
+    /// `CivetRequestExt::deadline` to give up early.
+    pub fn handler_deadline(&mut self, after: Duration) -> &mut Config {
+        self.handler_deadline = Some(after);
+        self
+    }
+
+    /// Rejects request bodies larger than `bytes` with a 413.
+    pub fn max_request_body(&mut self, bytes: u64) -> &mut Config {
+        self.max_request_body = Some(bytes);
+        self
+    }
+
+    /// Overrides settings for requests whose path is `prefix` or below it:
+    /// `/api` covers `/api` and `/api/users`, but not `/apis`.
+    ///
+    /// When several mounts match, the one with the longest prefix wins.
+    pub fn mount(&mut self, prefix: &str, mount: Mount) -> &mut Config {
+        self.mounts.push((prefix.to_string(), mount));
+        self
+    }
+
+    /// Believes what the proxies at these addresses (like `10.0.0.0/8`, or a
+    /// single address) say about the client in `Forwarded` or
+    /// `X-Forwarded-*` headers, for `remote_addr`, `scheme` and `host`.
+    pub fn trusted_proxies(&mut self, cidrs: &[&str]) -> &mut Config {
+        self.trusted_proxies.extend(cidrs.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Takes a request's ID from its `X-Request-Id` header, when it has a
+    /// well-formed one, instead of making one up. Only turn this on if a
+    /// proxy in front of the server sets the header or strips it.
+    pub fn trust_request_id(&mut self, trust: bool) -> &mut Config {
+        self.trust_request_id = trust;
+        self
+    }
+
+    /// Answers requests from clients that `acl` denies with a 403, without
+    /// calling the handler. The address checked is that of the other end of
+    /// the connection, not one a proxy forwarded.
+    pub fn access_control(&mut self, acl: AccessControl) -> &mut Config {
+        self.access_control = Some(acl);
+        self
+    }
+
+    /// Limits how fast responses are sent. To limit how often clients can
+    /// make requests, see `limit::RateLimit`.
+    pub fn throttle(&mut self, throttle: Throttle) -> &mut Config {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// The key used for signed and encrypted cookies, which handlers reach
+    /// through `CivetRequestExt::cookie_jar`.
+    #[cfg(feature = "secure-cookies")]
+    pub fn cookie_key(&mut self, key: Key) -> &mut Config {
+        self.cookie_key = Some(Arc::new(key));
+        self
+    }
+}
+
+impl Mount {
+    pub fn new() -> Mount {
+        Mount {
+            max_request_body: None,
+            handler_deadline: None,
+            access_control: None,
+        }
+    }
+
+    pub fn max_request_body(&mut self, bytes: u64) -> &mut Mount {
+        self.max_request_body = Some(bytes);
+        self
+    }
+
+    pub fn handler_deadline(&mut self, after: Duration) -> &mut Mount {
+        self.handler_deadline = Some(after);
+        self
+    }
+
+    pub fn access_control(&mut self, acl: AccessControl) -> &mut Mount {
+        self.access_control = Some(acl);
+        self
+    }
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl { rules: Vec::new(), invalid: Vec::new() }
+    }
+
+    /// Allows clients in `cidr`, a block like `10.0.0.0/8` or `fd00::/8`, or
+    /// a single address.
+    pub fn allow(&mut self, cidr: &str) -> &mut AccessControl {
+        self.rule(true, cidr)
+    }
+
+    /// Denies clients in `cidr`.
+    pub fn deny(&mut self, cidr: &str) -> &mut AccessControl {
+        self.rule(false, cidr)
+    }
+
+    fn rule(&mut self, allow: bool, cidr: &str) -> &mut AccessControl {
+        match cidr.parse() {
+            Ok(parsed) => self.rules.push((allow, parsed)),
+            Err(()) => self.invalid.push(cidr.to_string()),
+        }
+        self
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.rules.iter().rev()
+            .find(|&&(_, ref cidr)| cidr.contains(ip))
+            .map_or(false, |&(allow, _)| allow)
+    }
+}
+
+fn mount_for<'a>(config: &'a Config, path: &str) -> Option<&'a Mount> {
+    config.mounts.iter()
+        .filter(|&&(ref prefix, _)| under(path, prefix))
+        .max_by_key(|&&(ref prefix, _)| prefix.len())
+        .map(|&(_, ref mount)| mount)
+}
+
+/// Whether `path` is `prefix` or below it, so that `/public` covers
+/// `/public/a` but not `/public-secrets`.
+fn under(path: &str, prefix: &str) -> bool {
+    path.starts_with(prefix) &&
+        (path.len() == prefix.len() || prefix.ends_with('/') ||
+         path[prefix.len()..].starts_with('/'))
+}
+
+pub fn max_request_body(config: &Config, path: &str) -> Option<u64> {
+    mount_for(config, path).and_then(|m| m.max_request_body)
+        .or(config.max_request_body)
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle { rules: Vec::new(), invalid: Vec::new() }
+    }
+
+    /// Limits every connection to `bytes_per_sec`.
+    pub fn all(&mut self, bytes_per_sec: u64) -> &mut Throttle {
+        self.rules.push(("*".to_string(), bytes_per_sec));
+        self
+    }
+
+    /// Limits clients in `cidr`, like `10.0.0.0/8`. civetweb only knows
+    /// the addresses of IPv4 clients, so these must be IPv4 blocks.
+    pub fn network(&mut self, cidr: &str, bytes_per_sec: u64) -> &mut Throttle {
+        match cidr.parse::<Cidr>() {
+            Ok(ref parsed) if parsed.is_ipv4() && !cidr.contains(':') => {
+                self.rules.push((cidr.trim().to_string(), bytes_per_sec));
+            }
+            _ => self.invalid.push(cidr.to_string()),
+        }
+        self
+    }
+
+    /// Limits responses to requests whose path matches `pattern`, a
+    /// civetweb pattern like `/downloads/` or `**.iso$`.
+    pub fn path(&mut self, pattern: &str, bytes_per_sec: u64) -> &mut Throttle {
+        if pattern.is_empty() || pattern.contains(|c| c == ',' || c == '=') ||
+           pattern == "*" || pattern.parse::<Cidr>().is_ok() {
+            self.invalid.push(pattern.to_string());
+        } else {
+            self.rules.push((pattern.to_string(), bytes_per_sec));
+        }
+        self
+    }
+}
+
+/// How many worker threads civetweb will start.
+pub fn threads(config: &Config) -> u32 {
+    config.threads.unwrap_or(50)
+}
+
+pub fn listen_backlog(config: &Config) -> Option<u32> {
+    config.listen_backlog
+}
+
+pub fn connection_queue(config: &Config) -> Option<u32> {
+    config.connection_queue
+}
+
+pub fn shed_load(config: &Config) -> Option<Duration> {
+    config.shed_load
+}
+
+pub fn handler_deadline(config: &Config, path: &str) -> Option<Duration> {
+    mount_for(config, path).and_then(|m| m.handler_deadline)
+        .or(config.handler_deadline)
+}
+
+/// Whether a client at `ip` may make a request for `path`.
+pub fn allows(config: &Config, path: &str, ip: IpAddr) -> bool {
+    mount_for(config, path).and_then(|m| m.access_control.as_ref())
+        .or(config.access_control.as_ref())
+        .map_or(true, |acl| acl.allows(ip))
+}
+
+/// The first rule given to the `Throttle` that civetweb won't understand.
+pub fn invalid_throttle_rule(config: &Config) -> Option<&str> {
+    config.throttle.as_ref().and_then(|t| t.invalid.first()).map(|s| &s[..])
+}
+
+/// The first block given to an `AccessControl` that couldn't be parsed.
+pub fn invalid_access_rule(config: &Config) -> Option<&str> {
+    let mounts = config.mounts.iter().map(|&(_, ref m)| &m.access_control);
+    Some(&config.access_control).into_iter().chain(mounts)
+        .filter_map(|acl| acl.as_ref())
+        .flat_map(|acl| acl.invalid.first())
+        .next()
+        .map(|s| &s[..])
+}
+
+pub fn trusted_proxies(config: &Config) -> &[String] {
+    &config.trusted_proxies
+}
+
+pub fn trust_request_id(config: &Config) -> bool {
+    config.trust_request_id
+}
+
+pub fn decode_url(config: &Config) -> bool {
+    config.decode_url.unwrap_or(true)
+}
+
+#[cfg(feature = "secure-cookies")]
+pub fn cookie_key(config: &Config) -> Option<Arc<Key>> {
+    config.cookie_key.clone()
+}
+
+#[cfg(feature = "tls")]
+pub fn tls_hosts(config: &Config) -> &[(String, PathBuf, PathBuf)] {
+    &config.tls_hosts
+}
+
+/// How civetweb is told to verify client certificates, if it is.
+#[cfg(feature = "tls")]
+pub fn client_verification(config: &Config) -> Option<Verify> {
+    if config.ssl_verify_peer != Some(true) {
+        return None
+    }
+    Some(Verify {
+        ca_file: config.ssl_ca_file.clone(),
+        ca_path: config.ssl_ca_path.clone(),
+        depth: config.ssl_verify_depth,
+    })
+}
+
+#[cfg(feature = "tls")]
+fn has_tls_hosts(config: &Config) -> bool {
+    !config.tls_hosts.is_empty()
+}
+
+#[cfg(not(feature = "tls"))]
+fn has_tls_hosts(_config: &Config) -> bool {
+    false
+}
+
+fn listeners<'a>(config: &'a Config)
+                 -> Box<Iterator<Item=(SocketAddr, bool)> + 'a> {
+    let any = Ipv4Addr::new(0, 0, 0, 0).into();
+    Box::new(config.port.map(|port| (SocketAddr::new(any, port), false))
+                 .into_iter()
+                 .chain(config.listeners.iter().cloned()))
+}
+
+/// Whether civetweb will set up TLS for this configuration.
+pub fn uses_tls(config: &Config) -> bool {
+    config.ssl_certificate.is_some() || has_tls_hosts(config) ||
+        listeners(config).any(|(_, tls)| tls)
+}
+
+/// The index of the listener that accepted a connection on `local`.
+pub fn listener_index(config: &Config, local: &SocketAddr) -> Option<usize> {
+    listeners(config).position(|(addr, _)| {
+        addr.port() == local.port() &&
+            (addr.ip().is_unspecified() || addr.ip() == local.ip())
+    })
 }
 
 pub fn config_to_options(config: &Config) -> (Vec<CString>, Vec<*const c_char>) {
-    let Config { port, threads, enable_keep_alive } = *config;
+    let Config { threads, enable_keep_alive, .. } = *config;
     let mut options = Vec::new();
-    opt(&mut options, "listening_ports", port.map(|i| i.to_string()));
+    let any = Ipv4Addr::new(0, 0, 0, 0);
+    let ports = listeners(config).map(|(addr, tls)| {
+        let port = if addr.ip() == any {
+            addr.port().to_string()
+        } else {
+            addr.to_string()
+        };
+        if tls { port + "s" } else { port }
+    }).collect::<Vec<_>>();
+    opt(&mut options, "listening_ports", if ports.is_empty() {
+        None
+    } else {
+        Some(ports.join(","))
+    });
     opt(&mut options, "num_threads", threads.map(|i| i.to_string()));
     opt(&mut options, "enable_keep_alive", enable_keep_alive.map(|b| {
         (if b {"yes"} else {"no"}).to_string()
     }));
+    opt(&mut options, "request_timeout_ms", config.request_timeout.map(millis));
+    opt(&mut options, "keep_alive_timeout_ms",
+        config.keep_alive_timeout.map(millis));
+    opt(&mut options, "linger_timeout_ms", config.linger_timeout.map(millis));
+    opt(&mut options, "throttle", config.throttle.as_ref().map(|t| {
+        t.rules.iter().map(|&(ref key, limit)| format!("{}={}", key, limit))
+            .collect::<Vec<_>>().join(",")
+    }));
+    opt(&mut options, "ssl_certificate", path(&config.ssl_certificate));
+    opt(&mut options, "ssl_verify_peer", config.ssl_verify_peer.map(|b| {
+        (if b {"yes"} else {"no"}).to_string()
+    }));
+    opt(&mut options, "ssl_ca_file", path(&config.ssl_ca_file));
+    opt(&mut options, "ssl_ca_path", path(&config.ssl_ca_path));
+    opt(&mut options, "ssl_verify_depth",
+        config.ssl_verify_depth.map(|i| i.to_string()));
+    opt(&mut options, "decode_url", config.decode_url.map(|b| {
+        (if b {"yes"} else {"no"}).to_string()
+    }));
     let mut ptrs: Vec<*const c_char> = options.iter().map(|a| {
         a.as_ptr()
     }).collect();
     ptrs.push(0 as *const c_char);
     return (options, ptrs);
 
+    fn millis(duration: Duration) -> String {
+        (duration.as_secs() * 1000 +
+         duration.subsec_nanos() as u64 / 1_000_000).to_string()
+    }
+
+    fn path(path: &Option<PathBuf>) -> Option<String> {
+        path.as_ref().map(|p| p.to_string_lossy().into_owned())
+    }
+
     fn opt(v: &mut Vec<CString>, name: &str, opt: Option<String>) {
         if let Some(t) = opt {
             v.push(CString::new(name).unwrap());
@@ -0,0 +1,389 @@
+//! `Set-Cookie` headers for responses.
+//!
+//! With the `secure-cookies` feature, cookies can also be signed or
+//! encrypted with a `Key` derived from a server secret; see
+//! `Config::cookie_key` and `CivetRequestExt::cookie_jar`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use date;
+
+#[cfg(feature = "secure-cookies")]
+pub use self::secure::{Key, CookieJar};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+/// A cookie name, value, path or domain that can't go in a `Set-Cookie`
+/// header.
+#[derive(Clone, PartialEq, Debug)]
+pub struct InvalidCookie {
+    what: &'static str,
+    text: String,
+}
+
+impl fmt::Display for InvalidCookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid cookie {} {:?}", self.what, self.text)
+    }
+}
+
+impl Error for InvalidCookie {}
+
+impl Cookie {
+    /// A cookie called `name` set to `value`, with no attributes.
+    ///
+    /// Fails if `name` is empty or `name` or `value` has a character that
+    /// would end the cookie early in the `Set-Cookie` header: a control
+    /// character (CR and LF included) or `;`, and in `name`, `=` or
+    /// whitespace. `set_value`, `path` and `domain` check their argument
+    /// the same way.
+    pub fn new(name: &str, value: &str) -> Result<Cookie, InvalidCookie> {
+        if name.is_empty() ||
+           name.contains(|c: char| c == '=' || c.is_whitespace()) {
+            return Err(invalid("name", name))
+        }
+        try!(check("name", name));
+        try!(check("value", value));
+        Ok(Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+
+    /// A cookie telling the client to forget `name`. The path and domain
+    /// must match the ones the cookie was set with.
+    pub fn removal(name: &str) -> Result<Cookie, InvalidCookie> {
+        let mut cookie = try!(Cookie::new(name, ""));
+        cookie.max_age(Duration::from_secs(0)).expires(UNIX_EPOCH);
+        Ok(cookie)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn set_value(&mut self, value: &str)
+                     -> Result<&mut Cookie, InvalidCookie> {
+        try!(check("value", value));
+        self.value = value.to_string();
+        Ok(self)
+    }
+
+    pub fn path(&mut self, path: &str) -> Result<&mut Cookie, InvalidCookie> {
+        try!(check("path", path));
+        self.path = Some(path.to_string());
+        Ok(self)
+    }
+
+    pub fn domain(&mut self, domain: &str)
+                  -> Result<&mut Cookie, InvalidCookie> {
+        try!(check("domain", domain));
+        self.domain = Some(domain.to_string());
+        Ok(self)
+    }
+
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(&mut self, expires: SystemTime) -> &mut Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(&mut self, secure: bool) -> &mut Cookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(&mut self, http_only: bool) -> &mut Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(&mut self, same_site: SameSite) -> &mut Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Adds a `Set-Cookie` header for this cookie to the headers of a
+    /// response built with `civet::response`.
+    pub fn set(&self, headers: &mut HashMap<String, Vec<String>>) {
+        headers.entry("Set-Cookie".to_string())
+               .or_insert_with(Vec::new)
+               .push(self.to_string());
+    }
+}
+
+/// Fails if `s`, the cookie's `what`, would end the cookie or the header
+/// early: Set-Cookie has no way to escape `;` or line breaks.
+fn check(what: &'static str, s: &str) -> Result<(), InvalidCookie> {
+    if s.contains(|c: char| c == ';' || c.is_control()) {
+        return Err(invalid(what, s))
+    }
+    Ok(())
+}
+
+fn invalid(what: &'static str, s: &str) -> InvalidCookie {
+    InvalidCookie { what: what, text: s.to_string() }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}={}", self.name, self.value));
+        if let Some(ref path) = self.path {
+            try!(write!(f, "; Path={}", path));
+        }
+        if let Some(ref domain) = self.domain {
+            try!(write!(f, "; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            try!(write!(f, "; Max-Age={}", max_age.as_secs()));
+        }
+        if let Some(expires) = self.expires {
+            try!(write!(f, "; Expires={}", date::format(expires)));
+        }
+        if self.secure {
+            try!(write!(f, "; Secure"));
+        }
+        if self.http_only {
+            try!(write!(f, "; HttpOnly"));
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => try!(write!(f, "; SameSite=Strict")),
+            Some(SameSite::Lax) => try!(write!(f, "; SameSite=Lax")),
+            Some(SameSite::None) => try!(write!(f, "; SameSite=None")),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "secure-cookies")]
+mod secure {
+    use base64;
+    use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+    use ring::hmac;
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    use request::Cookies;
+    use super::Cookie;
+
+    /// Signs and encrypts cookie values. Signed cookies can be read but not
+    /// forged by the client; encrypted ("private") cookies can't be read
+    /// either.
+    pub struct Key {
+        signing: hmac::Key,
+        encryption: LessSafeKey,
+    }
+
+    impl Key {
+        /// Derives a key from a server secret, which should be at least 32
+        /// random bytes.
+        pub fn from_secret(secret: &[u8]) -> Key {
+            let master = hmac::Key::new(hmac::HMAC_SHA256, secret);
+            let signing = hmac::sign(&master, b"civet cookie signing");
+            let encryption = hmac::sign(&master, b"civet cookie encryption");
+            let encryption = UnboundKey::new(&AES_256_GCM, encryption.as_ref())
+                .unwrap();
+            Key {
+                signing: hmac::Key::new(hmac::HMAC_SHA256, signing.as_ref()),
+                encryption: LessSafeKey::new(encryption),
+            }
+        }
+
+        /// Appends a signature to the value of `cookie`.
+        pub fn sign(&self, cookie: &mut Cookie) {
+            let tag = hmac::sign(&self.signing, signed_data(cookie.name(),
+                                                            cookie.value())
+                                                   .as_bytes());
+            // A valid value with `.` and URL-safe base64 added stays valid.
+            cookie.value = format!("{}.{}", cookie.value(),
+                                   base64::encode_config(tag.as_ref(),
+                                                         base64::URL_SAFE_NO_PAD));
+        }
+
+        /// Checks the signature on a cookie sent back by the client,
+        /// returning the original value.
+        pub fn verify(&self, name: &str, value: &str) -> Option<String> {
+            let dot = match value.rfind('.') {
+                Some(dot) => dot,
+                None => return None,
+            };
+            let (value, tag) = (&value[..dot], &value[dot + 1..]);
+            let tag = match base64::decode_config(tag, base64::URL_SAFE_NO_PAD) {
+                Ok(tag) => tag,
+                Err(..) => return None,
+            };
+            hmac::verify(&self.signing, signed_data(name, value).as_bytes(),
+                         &tag).ok().map(|()| value.to_string())
+        }
+
+        /// Replaces the value of `cookie` with its encryption.
+        pub fn encrypt(&self, cookie: &mut Cookie) {
+            let mut nonce = [0; 12];
+            SystemRandom::new().fill(&mut nonce)
+                .expect("failed to generate a nonce");
+            let mut sealed = cookie.value().as_bytes().to_vec();
+            self.encryption.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(cookie.name().as_bytes()),
+                &mut sealed).expect("failed to encrypt cookie");
+
+            let mut value = nonce.to_vec();
+            value.extend_from_slice(&sealed);
+            cookie.value = base64::encode_config(&value, base64::URL_SAFE_NO_PAD);
+        }
+
+        /// Decrypts a cookie sent back by the client, returning the original
+        /// value.
+        pub fn decrypt(&self, name: &str, value: &str) -> Option<String> {
+            let mut value = match base64::decode_config(value,
+                                                        base64::URL_SAFE_NO_PAD) {
+                Ok(ref value) if value.len() > aead::NONCE_LEN => value.clone(),
+                _ => return None,
+            };
+            let mut sealed = value.split_off(aead::NONCE_LEN);
+            let nonce = match Nonce::try_assume_unique_for_key(&value) {
+                Ok(nonce) => nonce,
+                Err(..) => return None,
+            };
+            self.encryption.open_in_place(nonce, Aad::from(name.as_bytes()),
+                                          &mut sealed).ok()
+                .and_then(|plain| String::from_utf8(plain.to_vec()).ok())
+        }
+    }
+
+    fn signed_data(name: &str, value: &str) -> String {
+        format!("{}={}", name, value)
+    }
+
+    /// The request's cookies, read through the server's `Key`.
+    pub struct CookieJar<'a> {
+        key: &'a Key,
+        cookies: Cookies<'a>,
+    }
+
+    impl<'a> CookieJar<'a> {
+        pub fn new(key: &'a Key, cookies: Cookies<'a>) -> CookieJar<'a> {
+            CookieJar { key: key, cookies: cookies }
+        }
+
+        pub fn key(&self) -> &'a Key {
+            self.key
+        }
+
+        /// The value of the signed cookie `name`, if its signature is good.
+        pub fn signed(&self, name: &str) -> Option<String> {
+            self.cookies.get(name).and_then(|v| self.key.verify(name, v))
+        }
+
+        /// The value of the encrypted cookie `name`, if it decrypts.
+        pub fn private(&self, name: &str) -> Option<String> {
+            self.cookies.get(name).and_then(|v| self.key.decrypt(name, v))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{Cookie, SameSite};
+
+    #[test]
+    fn set_cookie_header() {
+        let mut cookie = Cookie::new("session", "abc").unwrap();
+        cookie.path("/").unwrap().domain("example.com").unwrap()
+              .max_age(Duration::from_secs(3600))
+              .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+              .secure(true).http_only(true).same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(),
+                   "session=abc; Path=/; Domain=example.com; Max-Age=3600; \
+                    Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; \
+                    SameSite=Lax");
+        assert_eq!(Cookie::removal("session").unwrap().to_string(),
+                   "session=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn rejects_line_breaks() {
+        let err = Cookie::new("session", "abc\r\nSet-Cookie: admin=1")
+            .unwrap_err();
+        assert_eq!(err.to_string(),
+                   "invalid cookie value \"abc\\r\\nSet-Cookie: admin=1\"");
+        let mut cookie = Cookie::new("session", "abc").unwrap();
+        assert!(cookie.set_value("abc\nSet-Cookie: admin=1").is_err());
+        assert_eq!(cookie.value(), "abc");
+    }
+
+    #[test]
+    fn rejects_attributes_in_values() {
+        let mut cookie = Cookie::new("session", "abc").unwrap();
+        assert!(cookie.path("/; Domain=example.com").is_err());
+        assert!(cookie.domain("example.com; Secure").is_err());
+        assert_eq!(cookie.to_string(), "session=abc");
+    }
+
+    #[test]
+    fn rejects_separators_in_names() {
+        assert!(Cookie::new("a=b", "c").is_err());
+        assert!(Cookie::new("a b", "c").is_err());
+        assert!(Cookie::new("", "c").is_err());
+        assert!(Cookie::removal("a;b").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "secure-cookies")]
+    fn signed_and_private() {
+        use super::Key;
+
+        let key = Key::from_secret(b"an example secret that is long enough");
+        let mut cookie = Cookie::new("user", "alice").unwrap();
+        key.sign(&mut cookie);
+        assert_eq!(key.verify("user", cookie.value()), Some("alice".to_string()));
+        assert_eq!(key.verify("admin", cookie.value()), None);
+        let forged = cookie.value().replace("alice", "mallory");
+        assert_eq!(key.verify("user", &forged), None);
+
+        let mut cookie = Cookie::new("user", "alice").unwrap();
+        key.encrypt(&mut cookie);
+        assert!(!cookie.value().contains("alice"));
+        assert_eq!(key.decrypt("user", cookie.value()), Some("alice".to_string()));
+        assert_eq!(key.decrypt("admin", cookie.value()), None);
+        let other = Key::from_secret(b"a different secret that is long enough");
+        assert_eq!(other.decrypt("user", cookie.value()), None);
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static DAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+static MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                                     "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = secs / 86400;
+    let secs = secs % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[((days + 4) % 7) as usize], day, MONTHS[(month - 1) as usize],
+            year, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Parses an HTTP date in any of the formats RFC 7231 asks recipients to
+/// accept: `Sun, 06 Nov 1994 08:49:37 GMT`, the obsolete
+/// `Sunday, 06-Nov-94 08:49:37 GMT`, and asctime's
+/// `Sun Nov  6 08:49:37 1994`.
+pub fn parse(s: &str) -> Option<SystemTime> {
+    let parts = s.split(|c| c == ' ' || c == ',')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    let (day, month, year, time) = match &parts[..] {
+        &[_, day, month, year, time, "GMT"] => (day, month, year, time),
+        &[_, date, time, "GMT"] => {
+            let date = date.split('-').collect::<Vec<_>>();
+            if date.len() != 3 || date[2].len() != 2 {
+                return None
+            }
+            (date[0], date[1], date[2], time)
+        }
+        &[_, month, day, time, year] => (day, month, year, time),
+        _ => return None,
+    };
+
+    let day = match day.parse::<u32>() {
+        Ok(day) if day >= 1 && day <= 31 => day,
+        _ => return None,
+    };
+    let month = match MONTHS.iter().position(|&m| m == month) {
+        Some(i) => i as u32 + 1,
+        None => return None,
+    };
+    let mut year = match year.parse::<i64>() {
+        Ok(year) => year,
+        Err(..) => return None,
+    };
+    if year < 100 {
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+    // Four digits is all the formats have room for, and keeps the
+    // arithmetic below from overflowing.
+    if year < 1970 || year > 9999 {
+        return None
+    }
+    let time = time.split(':').map(|n| n.parse::<u64>().ok()).collect::<Vec<_>>();
+    let (hour, minute, second) = match &time[..] {
+        &[Some(h), Some(m), Some(s)] if h < 24 && m < 60 && s < 61 => (h, m, s),
+        _ => return None,
+    };
+
+    let days = days_from_civil(year, month, day);
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Howard Hinnant's `days_from_civil`: (y, m, d) to days since 1970-01-01.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Howard Hinnant's `civil_from_days`: days since 1970-01-01 to (y, m, d).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format, parse};
+
+    #[test]
+    fn parses() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(time));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(time));
+        assert_eq!(parse(&format(time)), Some(time));
+        let leap = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(parse("Tue, 29 Feb 2000 00:00:00 GMT"), Some(leap));
+
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 1960 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 300000000000 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 10000 08:49:37 GMT"), None);
+        assert_eq!(parse("yesterday"), None);
+        assert_eq!(parse(""), None);
+    }
+}
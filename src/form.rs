@@ -0,0 +1,394 @@
+//! Streaming access to HTML form submissions, built on civetweb's
+//! `mg_handle_form_request`.
+//!
+//! The query string of a `GET` request, `application/x-www-form-urlencoded`
+//! bodies and `multipart/form-data` bodies are all understood. Fields are
+//! handed to a `Visitor` as they are parsed, so uploads never have to be held
+//! in memory; `parse` collects a whole submission into a `Form` for the
+//! common case.
+//!
+//! civetweb parses the body, but reads it through the request's own body
+//! reader, so `Config::max_request_body`, the handler deadline and the
+//! count of bytes read apply to form bodies, chunked ones included, as they
+//! do to any other.
+
+use std::any::Any;
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+use conduit::Request;
+use libc::{c_char, c_int, c_longlong, c_void, size_t};
+
+use random;
+use raw;
+
+/// A form field, as announced by the client.
+pub struct Field {
+    pub name: String,
+    /// The name of the file on the client, for file inputs.
+    pub filename: Option<String>,
+}
+
+/// What to do with the value of a field.
+pub enum Storage {
+    /// Ignore the value.
+    Skip,
+    /// Hand the value to `Visitor::data`, a piece at a time.
+    Read,
+    /// Write the value to a file at this path, replacing any existing file.
+    /// Parsing fails with `InvalidInput` if the path isn't valid UTF-8, is
+    /// longer than civetweb allows, or contains a NUL.
+    File(PathBuf),
+    /// Write the value to a new file in the temporary directory.
+    TempFile,
+    /// Stop parsing; none of the remaining fields are visited.
+    Abort,
+}
+
+pub trait Visitor {
+    /// Decides what to do with a field that was just found.
+    fn field(&mut self, field: &Field) -> Storage;
+
+    /// Receives the next piece of a value the visitor asked to `Read`.
+    fn data(&mut self, _field: &Field, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once a value has been completely written to `path`.
+    fn stored(&mut self, _field: &Field, _path: &Path,
+              _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `visitor` over the fields of the form submitted with `req`,
+/// returning the number of fields found.
+pub fn visit<V: Visitor>(req: &mut Request, visitor: &mut V)
+                         -> io::Result<usize> {
+    let conn = try!(::raw_connection(req));
+    let mut state = State {
+        body: req.body(),
+        visitor: visitor,
+        field: None,
+        temp_file: None,
+        error: None,
+        panic: None,
+    };
+    let mut handler = raw::MgFormDataHandler {
+        field_found: field_found,
+        field_get: field_get,
+        field_store: field_store,
+        user_data: &mut state as *mut State as *mut c_void,
+        read: read,
+    };
+    let ret = raw::handle_form_request(&conn, &mut handler);
+    state.remove_temp_file();
+
+    if let Some(payload) = state.panic.take() {
+        panic::resume_unwind(payload)
+    }
+    if let Some(err) = state.error.take() {
+        return Err(err)
+    }
+    if ret < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "malformed form data"))
+    }
+    Ok(ret as usize)
+}
+
+/// A complete form submission, with uploaded files in temporary files.
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Form {
+    /// The first value given for `name`, if any. Names are compared
+    /// ignoring ASCII case, as in `request::Query::get`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    /// The first file uploaded as `name`, if any, comparing names like `get`.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|f| f.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A file uploaded with a form. The temporary file is removed when this is
+/// dropped, unless it has been `persist`ed.
+pub struct UploadedFile {
+    pub name: String,
+    pub filename: String,
+    pub size: u64,
+    path: PathBuf,
+}
+
+impl UploadedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the upload to `to`, keeping it around after this is dropped.
+    pub fn persist(mut self, to: &Path) -> io::Result<()> {
+        let path = mem::replace(&mut self.path, PathBuf::new());
+        fs::rename(&path, to)
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Reads the whole form submitted with `req`. Text fields must be UTF-8.
+pub fn parse(req: &mut Request) -> io::Result<Form> {
+    let mut collector = Collector {
+        form: Form { fields: Vec::new(), files: Vec::new() },
+        value: None,
+    };
+    try!(visit(req, &mut collector));
+    try!(collector.finish_value());
+    Ok(collector.form)
+}
+
+struct Collector {
+    form: Form,
+    value: Option<(String, Vec<u8>)>,
+}
+
+impl Collector {
+    fn finish_value(&mut self) -> io::Result<()> {
+        if let Some((name, value)) = self.value.take() {
+            let value = try!(String::from_utf8(value).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               "form field is not valid UTF-8")
+            }));
+            self.form.fields.push((name, value));
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for Collector {
+    fn field(&mut self, field: &Field) -> Storage {
+        if self.finish_value().is_err() {
+            return Storage::Abort
+        }
+        match field.filename {
+            Some(_) => Storage::TempFile,
+            None => {
+                self.value = Some((field.name.clone(), Vec::new()));
+                Storage::Read
+            }
+        }
+    }
+
+    fn data(&mut self, _field: &Field, data: &[u8]) -> io::Result<()> {
+        if let Some((_, ref mut value)) = self.value {
+            value.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn stored(&mut self, field: &Field, path: &Path,
+              size: u64) -> io::Result<()> {
+        self.form.files.push(UploadedFile {
+            name: field.name.clone(),
+            filename: field.filename.clone().unwrap_or(String::new()),
+            size: size,
+            path: path.to_path_buf(),
+        });
+        Ok(())
+    }
+}
+
+struct State<'a> {
+    body: &'a mut Read,
+    visitor: &'a mut Visitor,
+    field: Option<Field>,
+    /// A temporary file created for the current field, until civetweb has
+    /// stored the value in it.
+    temp_file: Option<PathBuf>,
+    error: Option<io::Error>,
+    panic: Option<Box<Any + Send>>,
+}
+
+impl<'a> State<'a> {
+    /// Removes the temporary file of a field civetweb didn't store, like one
+    /// it failed to open or that parsing stopped before.
+    fn remove_temp_file(&mut self) {
+        if let Some(path) = self.temp_file.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// The callbacks below are called from C, so a panic in the visitor is caught
+// here and resumed once `mg_handle_form_request` has returned.
+fn guard<F>(user_data: *mut c_void, on_panic: c_int, f: F) -> c_int
+    where F: FnOnce(&mut State) -> c_int
+{
+    let state = unsafe { &mut *(user_data as *mut State) };
+    if state.panic.is_some() {
+        return on_panic
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *state))) {
+        Ok(ret) => ret,
+        Err(payload) => {
+            state.panic = Some(payload);
+            on_panic
+        }
+    }
+}
+
+extern fn field_found(key: *const c_char, filename: *const c_char,
+                      path: *mut c_char, pathlen: size_t,
+                      user_data: *mut c_void) -> c_int {
+    guard(user_data, raw::FORM_FIELD_STORAGE_ABORT, |state| {
+        state.remove_temp_file();
+        if state.error.is_some() {
+            return raw::FORM_FIELD_STORAGE_ABORT
+        }
+        let field = Field {
+            name: to_string(key).unwrap_or(String::new()),
+            filename: to_string(filename),
+        };
+        let storage = state.visitor.field(&field);
+        state.field = Some(field);
+
+        let target = match storage {
+            Storage::Skip => return raw::FORM_FIELD_STORAGE_SKIP,
+            Storage::Read => return raw::FORM_FIELD_STORAGE_GET,
+            Storage::Abort => return raw::FORM_FIELD_STORAGE_ABORT,
+            Storage::File(target) => target,
+            Storage::TempFile => match temp_file() {
+                Ok(path) => {
+                    state.temp_file = Some(path.clone());
+                    path
+                }
+                Err(err) => {
+                    state.error = Some(err);
+                    return raw::FORM_FIELD_STORAGE_ABORT
+                }
+            },
+        };
+        // civetweb takes the path as a C string, and opens it as UTF-8 on
+        // Windows, so only a UTF-8 path is sure to name the file we mean.
+        let target = match target.to_str() {
+            Some(target) => target.as_bytes().to_vec(),
+            None => Vec::new(),
+        };
+        if target.is_empty() || target.len() >= pathlen || target.contains(&0) {
+            state.remove_temp_file();
+            state.error = Some(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "unusable upload path"));
+            return raw::FORM_FIELD_STORAGE_ABORT
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(target.as_ptr() as *const c_char, path,
+                                     target.len());
+            *path.offset(target.len() as isize) = 0;
+        }
+        raw::FORM_FIELD_STORAGE_STORE
+    })
+}
+
+extern fn field_get(_key: *const c_char, value: *const c_char,
+                    valuelen: size_t, user_data: *mut c_void) -> c_int {
+    guard(user_data, 0, |state| {
+        if state.error.is_some() || valuelen == 0 {
+            return 0
+        }
+        if let Some(ref field) = state.field {
+            let data = unsafe {
+                slice::from_raw_parts(value as *const u8, valuelen)
+            };
+            if let Err(err) = state.visitor.data(field, data) {
+                state.error = Some(err);
+            }
+        }
+        0
+    })
+}
+
+extern fn field_store(path: *const c_char, file_size: c_longlong,
+                      user_data: *mut c_void) -> c_int {
+    guard(user_data, 0, |state| {
+        // The file is the visitor's now, however this turns out.
+        state.temp_file = None;
+        if state.error.is_some() {
+            return 0
+        }
+        if let (Some(ref field), Some(path)) = (state.field.as_ref(),
+                                                 to_string(path)) {
+            let path = Path::new(&path);
+            if let Err(err) = state.visitor.stored(field, path,
+                                                   file_size as u64) {
+                state.error = Some(err);
+            }
+        }
+        0
+    })
+}
+
+extern fn read(user_data: *mut c_void, buf: *mut c_void,
+               len: size_t) -> c_int {
+    guard(user_data, -1, |state| {
+        if state.error.is_some() {
+            return -1
+        }
+        let buf = unsafe { slice::from_raw_parts_mut(buf as *mut u8, len) };
+        match state.body.read(buf) {
+            Ok(n) => n as c_int,
+            Err(err) => {
+                state.error = Some(err);
+                -1
+            }
+        }
+    })
+}
+
+fn to_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None
+    }
+    let s = unsafe { CStr::from_ptr(s) }.to_string_lossy();
+    if s.is_empty() { None } else { Some(s.into_owned()) }
+}
+
+/// Creates an empty file for an upload in the temporary directory, under a
+/// name that can't be guessed. civetweb opens the path it is given with a
+/// plain `fopen`, which would follow a symlink planted there, so the file
+/// is created here first, exclusively and readable only by us.
+fn temp_file() -> io::Result<PathBuf> {
+    loop {
+        let name = format!("civet-upload-{}", try!(random::hex(16)));
+        let path = env::temp_dir().join(name);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        match options.open(&path) {
+            Ok(..) => return Ok(path),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
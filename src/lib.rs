@@ -1,36 +1,93 @@
+extern crate base64;
 extern crate conduit;
 extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate semver;
 extern crate civet_sys as ffi;
+#[cfg(feature = "secure-cookies")]
+extern crate ring;
+#[cfg(feature = "tls")]
+extern crate foreign_types;
+#[cfg(feature = "tls")]
+extern crate openssl;
+#[cfg(feature = "tls")]
+extern crate openssl_sys;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "compression")]
+extern crate brotli;
+#[cfg(feature = "compression")]
+extern crate flate2;
 
+use std::any::Any;
+use std::borrow::Cow;
+use std::cmp;
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::io::prelude::*;
 use std::io::{self, BufWriter};
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::str::FromStr;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::str::{self, FromStr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
 
 use conduit::{Handler, Extensions, TypeMap, Method, Scheme, Host};
 
 use raw::{RequestInfo,Header};
-use raw::{get_header,get_headers,get_request_info};
+use raw::{get_header,request_headers,get_request_info};
 use status::{ToStatusCode};
 
-pub use config::Config;
+pub use config::{AccessControl, Config, Mount, Throttle};
+pub use request::CivetRequestExt;
+pub use stats::{Histogram, ServerStats};
+pub use vhost::VirtualHosts;
 
 mod raw;
 mod config;
+mod date;
+mod proxy;
+mod random;
+mod request_id;
+mod stats;
+#[cfg(feature = "tls")]
+mod ssl;
+pub mod auth;
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod conditional;
+pub mod cookie;
+pub mod form;
+pub mod limit;
+pub mod range;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod request;
 pub mod status;
+pub mod tls;
+pub mod vhost;
 
 pub struct Connection<'a> {
     request: CivetRequest<'a>,
     written: bool,
+    status: Option<u32>,
+    bytes_written: u64,
 }
 
 pub struct CivetRequest<'a> {
     conn: &'a raw::Connection,
     request_info: RequestInfo<'a>,
+    path: String,
     headers: Headers<'a>,
-    extensions: Extensions
+    extensions: Extensions,
+    body_limit: Option<u64>,
+    body_read: u64,
+    expect_continue: bool,
 }
 
 fn ver(major: u64, minor: u64) -> semver::Version {
@@ -43,6 +100,19 @@ fn ver(major: u64, minor: u64) -> semver::Version {
     }
 }
 
+impl<'a> CivetRequest<'a> {
+    fn forwarded(&self) -> Option<&proxy::Forwarded> {
+        self.extensions.find::<proxy::Forwarded>()
+    }
+
+    /// The address of the other end of the connection, which may be a proxy.
+    fn peer_addr(&self) -> SocketAddr {
+        let ip = self.request_info.remote_addr();
+        let ip = IpAddr::from_str(ip).unwrap_or_else(|_| Ipv4Addr::new(0, 0, 0, 0).into());
+        SocketAddr::new(ip, self.request_info.remote_port())
+    }
+}
+
 impl<'a> conduit::Request for CivetRequest<'a> {
     fn http_version(&self) -> semver::Version {
         let version = self.request_info.http_version().unwrap();
@@ -74,6 +144,9 @@ impl<'a> conduit::Request for CivetRequest<'a> {
     }
 
     fn scheme(&self) -> Scheme {
+        if let Some(scheme) = self.forwarded().and_then(|f| f.scheme) {
+            return scheme
+        }
         if self.request_info.is_ssl() {
             Scheme::Https
         } else {
@@ -81,8 +154,26 @@ impl<'a> conduit::Request for CivetRequest<'a> {
         }
     }
 
+    /// The host the client asked for. IP literals come back as sockets,
+    /// with the scheme's default port if the `Host` header has none; when
+    /// there is no usable `Host` header, this is the local address the
+    /// request arrived on.
     fn host(&self) -> Host {
-        Host::Name(get_header(self.conn, "Host").unwrap())
+        let host = requested_host(self).and_then(split_host);
+        match host {
+            Some((name, port)) => match IpAddr::from_str(name) {
+                Ok(ip) => {
+                    let port = port.unwrap_or(default_port(self.scheme()));
+                    Host::Socket(SocketAddr::new(ip, port))
+                }
+                Err(..) => Host::Name(name),
+            },
+            None => {
+                let unspecified = Ipv4Addr::new(0, 0, 0, 0).into();
+                Host::Socket(raw::local_addr(self.conn)
+                    .unwrap_or(SocketAddr::new(unspecified, 0)))
+            }
+        }
     }
 
     fn virtual_root(&self) -> Option<&str> {
@@ -90,7 +181,7 @@ impl<'a> conduit::Request for CivetRequest<'a> {
     }
 
     fn path(&self) -> &str {
-        self.request_info.url().unwrap()
+        &self.path
     }
 
     fn query_string(&self) -> Option<&str> {
@@ -98,9 +189,10 @@ impl<'a> conduit::Request for CivetRequest<'a> {
     }
 
     fn remote_addr(&self) -> SocketAddr {
-        let ip = self.request_info.remote_addr();
-        let ip = IpAddr::from_str(ip).unwrap_or_else(|_| Ipv4Addr::new(0, 0, 0, 0).into());
-        SocketAddr::new(ip, self.request_info.remote_port())
+        match self.forwarded() {
+            Some(forwarded) => forwarded.addr,
+            None => self.peer_addr(),
+        }
     }
 
     fn content_length(&self) -> Option<u64> {
@@ -129,19 +221,35 @@ pub fn response<S: ToStatusCode, R: Read + Send + 'static>(status: S,
 }
 
 impl<'a> Connection<'a> {
-    fn new(conn: &raw::Connection) -> Result<Connection, String> {
+    /// `decoded` says whether civetweb has already percent-decoded the path;
+    /// see `Config::decode_url`.
+    fn new(conn: &raw::Connection, decoded: bool) -> Result<Connection, String> {
         match request_info(conn) {
             Ok(info) => {
+                let mut extensions = TypeMap::new();
+                extensions.insert(RawConnection(*conn));
+                let path = if decoded {
+                    info.url().unwrap().to_string()
+                } else {
+                    extensions.insert(UndecodedTarget);
+                    decode_path(info.url().unwrap())
+                };
                 let request = CivetRequest {
                     conn: conn,
+                    expect_continue: expects_continue(conn, &info),
+                    path: path,
                     request_info: info,
                     headers: Headers { conn: conn },
-                    extensions: TypeMap::new()
+                    extensions: extensions,
+                    body_limit: None,
+                    body_read: 0,
                 };
 
                 Ok(Connection {
                     request: request,
                     written: false,
+                    status: None,
+                    bytes_written: 0,
                 })
             },
             Err(err) => Err(err)
@@ -152,32 +260,308 @@ impl<'a> Connection<'a> {
 
 impl<'a> Write for Connection<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A `BufWriter` unwinding out of a panicking handler would otherwise
+        // flush a partial response; `internal_handler` decides what to send.
+        if thread::panicking() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "handler panicked"))
+        }
         self.written = true;
         match raw::write(self.request.conn, buf) {
             n if n < 0 => Err(io::Error::new(io::ErrorKind::Other,
                                              &format!("write error ({})", n)[..])),
-            n => Ok(n as usize)
+            n => {
+                self.bytes_written += n as u64;
+                Ok(n as usize)
+            }
         }
     }
     fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
 
+impl<'a> CivetRequest<'a> {
+    fn body_too_large(&self) -> bool {
+        self.body_limit.map_or(false, |limit| self.body_read > limit)
+    }
+
+    /// The ID `internal_handler` gave the request.
+    fn request_id(&self) -> &str {
+        self.extensions.find::<RequestId>().map_or("-", |&RequestId(ref id)| &id[..])
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.extensions.find::<Deadline>()
+            .map_or(false, |&Deadline(deadline)| Instant::now() >= deadline)
+    }
+}
+
+/// Lets civet-specific APIs find the connection behind a `conduit::Request`.
+/// Only civet inserts it, and it is dropped along with the request.
+struct RawConnection(raw::Connection);
+
+/// Marks a request whose target civetweb left as the client sent it; see
+/// `Config::decode_url`.
+struct UndecodedTarget;
+
+/// The index of the configured listener a request arrived on.
+struct Listener(usize);
+
+/// The user one of the `auth` handlers authenticated the request as.
+struct RemoteUser(String);
+
+/// When the handler's time is up; see `Config::handler_deadline`.
+struct Deadline(Instant);
+
+/// The ID logs and the `X-Request-Id` response header know the request by.
+struct RequestId(String);
+
+/// The trailer fields that followed a chunked body, once it has been read to
+/// its end.
+struct Trailers(Vec<(String, String)>);
+
+/// Lets `metrics::Metrics` see the statistics of the server it runs in.
+#[cfg(feature = "metrics")]
+struct Stats(Arc<stats::Recorder>);
+
+/// Counts a request as active for as long as it is alive.
+struct Active<'a> {
+    count: &'a AtomicUsize,
+}
+
+impl<'a> Active<'a> {
+    fn enter(count: &'a AtomicUsize) -> Active<'a> {
+        count.fetch_add(1, Ordering::SeqCst);
+        Active { count: count }
+    }
+}
+
+impl<'a> Drop for Active<'a> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn raw_connection(req: &conduit::Request) -> io::Result<raw::Connection> {
+    match req.extensions().find::<RawConnection>() {
+        Some(&RawConnection(conn)) => Ok(conn),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "not a civet request")),
+    }
+}
+
+/// The `Host` the client sent, or the one a trusted proxy says it sent.
+fn requested_host<R: conduit::Request + ?Sized>(req: &R) -> Option<&str> {
+    let forwarded = req.extensions().find::<proxy::Forwarded>()
+        .and_then(|f| f.host.as_ref().map(|host| &host[..]));
+    forwarded.or_else(|| {
+        req.headers().find("Host").and_then(|values| values.into_iter().next())
+    })
+}
+
+/// Splits a `Host` header into the host and the port, if one is given.
+/// IPv6 literals lose their brackets.
+fn split_host(host: &str) -> Option<(&str, Option<u16>)> {
+    let host = host.trim();
+    let (name, port) = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => (&host[1..end], &host[end + 1..]),
+            None => return None,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(colon) => (&host[..colon], &host[colon..]),
+            None => (host, ""),
+        }
+    };
+    if name.is_empty() {
+        return None
+    }
+    match port {
+        "" => Some((name, None)),
+        _ if port.starts_with(':') => {
+            port[1..].parse().ok().map(|port| (name, Some(port)))
+        }
+        _ => None,
+    }
+}
+
+/// The form of a host name that lookups are keyed on: lowercase, without
+/// the trailing dot of a fully qualified name.
+fn host_key(name: &str) -> String {
+    let name = if name.ends_with('.') { &name[..name.len() - 1] } else { name };
+    name.to_ascii_lowercase()
+}
+
+/// The first value of the response header `name`, matched
+/// case-insensitively since handlers spell header names however they like.
+fn response_header<'a>(headers: &'a HashMap<String, Vec<String>>, name: &str)
+                       -> Option<&'a str> {
+    headers.iter()
+        .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(|value| &value[..])
+}
+
+fn remove_response_header(headers: &mut HashMap<String, Vec<String>>, name: &str) {
+    headers.retain(|key, _| !key.eq_ignore_ascii_case(name));
+}
+
+fn default_port(scheme: Scheme) -> u16 {
+    match scheme {
+        Scheme::Http => 80,
+        Scheme::Https => 443,
+    }
+}
+
+/// Decodes the path of a request the way civetweb would with `decode_url`
+/// on: `%XX` escapes are decoded, then repeated slashes and `..` are removed
+/// again in case the escapes produced any.
+fn decode_path(path: &str) -> String {
+    let path = url_decode(path, false);
+    let path = path.as_bytes();
+    let mut ret = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i + 1 < path.len() && &path[i..i + 2] == b".." {
+        i += 1;
+    }
+    while i < path.len() {
+        let b = path[i];
+        ret.push(b);
+        i += 1;
+        if b == b'/' || b == b'\\' {
+            while i < path.len() {
+                if path[i] == b'/' || path[i] == b'\\' {
+                    i += 1;
+                } else if path[i..].starts_with(b"..") {
+                    i += 2;
+                } else {
+                    break
+                }
+            }
+        }
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in form data, as
+/// `mg_url_decode` does. Malformed escapes are kept as they are.
+fn url_decode(s: &str, form: bool) -> Cow<str> {
+    if !s.contains(|c| c == '%' || (form && c == '+')) {
+        return Cow::Borrowed(s)
+    }
+
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(a), Some(b)) => {
+                        ret.push(a << 4 | b);
+                        i += 3;
+                        continue
+                    }
+                    _ => ret.push(b'%'),
+                }
+            }
+            b'+' if form => ret.push(b' '),
+            b => ret.push(b),
+        }
+        i += 1;
+    }
+    Cow::Owned(String::from_utf8_lossy(&ret).into_owned())
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+fn expects_continue(conn: &raw::Connection, info: &RequestInfo) -> bool {
+    info.http_version() == Some("1.1") &&
+        get_header(conn, "Expect")
+            .map_or(false, |e| e.eq_ignore_ascii_case("100-continue"))
+}
+
 impl<'a> Read for CivetRequest<'a> {
     fn read(&mut self, buf: &mut[u8]) -> io::Result<usize> {
+        // The client is waiting for our go-ahead before sending the body, and
+        // the handler asking for it is the go-ahead.
+        if self.expect_continue {
+            self.expect_continue = false;
+            let status = b"HTTP/1.1 100 Continue\r\n\r\n";
+            if raw::write(self.conn, status) < 0 {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "failed to send 100 Continue"))
+            }
+        }
+
+        // Read at most one byte past the limit, which is enough to tell
+        // whether the body is too large without buffering any more of it.
+        let buf = match self.body_limit {
+            Some(limit) => {
+                let left = (limit + 1).saturating_sub(self.body_read);
+                let len = cmp::min(left, buf.len() as u64) as usize;
+                &mut buf[..len]
+            }
+            None => buf,
+        };
+        if self.body_too_large() {
+            return Err(body_too_large())
+        }
+        if self.past_deadline() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      "handler deadline passed"))
+        }
         match raw::read(self.conn, buf) {
             n if n < 0 => Err(io::Error::new(io::ErrorKind::Other,
                                              &format!("read error ({})", n)[..])),
-            n => Ok(n as usize)
+            0 if buf.len() > 0 && self.extensions.find::<Trailers>().is_none() => {
+                let trailers = try!(read_trailers(self.conn));
+                if let Some(trailers) = trailers {
+                    self.extensions.insert(Trailers(trailers));
+                }
+                Ok(0)
+            }
+            n => {
+                self.body_read += n as u64;
+                if self.body_too_large() {
+                    Err(body_too_large())
+                } else {
+                    Ok(n as usize)
+                }
+            }
         }
     }
 }
 
-impl<'a> Drop for Connection<'a> {
-    fn drop(&mut self) {
-        if !self.written {
-            let _ = writeln!(self, "HTTP/1.1 500 Internal Server Error");
+/// The most a chunked body's trailer section may take up.
+const MAX_TRAILERS: usize = 8192;
+
+/// Reads and parses the trailer section after a chunked body that has been
+/// read to its end, or returns `None` if the body isn't chunked.
+fn read_trailers(conn: &raw::Connection) -> io::Result<Option<Vec<(String, String)>>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData,
+                                      "malformed chunked body trailers");
+    let mut buf = vec![0; MAX_TRAILERS];
+    let len = match raw::read_trailers(conn, &mut buf) {
+        n if n < 0 => return Err(malformed()),
+        0 => return Ok(None),
+        n => n as usize,
+    };
+    let section = try!(str::from_utf8(&buf[..len]).map_err(|_| malformed()));
+    section.split("\r\n").filter(|line| !line.is_empty()).map(|line| {
+        match line.find(':') {
+            Some(i) if i > 0 && !line[..i].contains(char::is_whitespace) => {
+                Ok((line[..i].to_string(), line[i + 1..].trim().to_string()))
+            }
+            _ => Err(malformed()),
         }
-    }
+    }).collect::<io::Result<_>>().map(Some)
+}
+
+fn body_too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "request body too large")
 }
 
 pub struct Headers<'a> {
@@ -205,7 +589,7 @@ pub struct HeaderIterator<'a> {
 
 impl<'a> HeaderIterator<'a> {
     fn new<'b>(conn: &'b raw::Connection) -> HeaderIterator<'b> {
-        HeaderIterator { headers: get_headers(conn), position: 0 }
+        HeaderIterator { headers: request_headers(conn), position: 0 }
     }
 }
 
@@ -225,28 +609,239 @@ impl<'a> Iterator for HeaderIterator<'a> {
     }
 }
 
-pub struct Server(raw::Server<Box<Handler + 'static + Sync>>);
+pub struct Server(raw::Server<ServerContext>);
+
+struct ServerContext {
+    handler: Box<Handler + 'static + Sync>,
+    config: Config,
+    proxies: Vec<proxy::Cidr>,
+    stats: Arc<stats::Recorder>,
+    request_ids: request_id::Generator,
+    #[cfg(feature = "tls")]
+    certificates: Arc<ssl::Certificates>,
+}
 
 impl Server {
     pub fn start<H: Handler + 'static + Sync>(options: Config, handler: H)
         -> io::Result<Server>
     {
         fn internal_handler(conn: &mut raw::Connection,
-                            handler: &Box<Handler + 'static + Sync>)
+                            context: &ServerContext)
                             -> Result<(), ()> {
-            let mut connection = Connection::new(conn).unwrap();
-            let response = handler.call(&mut connection.request);
-            let mut writer = BufWriter::new(connection);
+            let start = Instant::now();
+            let decoded = config::decode_url(&context.config);
+            let mut connection = Connection::new(conn, decoded).unwrap();
+            let id = get_header(conn, request_id::HEADER)
+                .filter(|id| config::trust_request_id(&context.config) &&
+                             request_id::is_valid(id))
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| context.request_ids.next());
+            connection.request.extensions.insert(RequestId(id));
+
+            #[cfg(feature = "tracing")]
+            let result = {
+                let span = {
+                    let request = &connection.request;
+                    tracing::info_span!("request",
+                                        id = request.request_id(),
+                                        method = request.request_info.method()
+                                                     .unwrap_or("-"),
+                                        path = &request.path[..],
+                                        status = tracing::field::Empty,
+                                        duration = tracing::field::Empty)
+                };
+                let _entered = span.enter();
+                let result = handle(conn, &mut connection, context);
+                if let Some(status) = connection.status {
+                    span.record("status", &status);
+                }
+                span.record("duration", &tracing::field::debug(start.elapsed()));
+                result
+            };
+            #[cfg(not(feature = "tracing"))]
+            let result = handle(conn, &mut connection, context);
+
+            context.stats.record(connection.request.request_info.method()
+                                     .unwrap_or("-"),
+                                 connection.status,
+                                 connection.request.body_read,
+                                 connection.bytes_written,
+                                 start.elapsed());
+            result
+        }
+
+        fn handle(conn: &raw::Connection, connection: &mut Connection,
+                  context: &ServerContext) -> Result<(), ()> {
+            connection.request.body_limit =
+                config::max_request_body(&context.config,
+                                         &connection.request.path);
+            let peer = connection.request.peer_addr();
+            if !config::allows(&context.config, &connection.request.path,
+                               peer.ip()) {
+                context.stats.denied.fetch_add(1, Ordering::Relaxed);
+                raw::force_close(conn);
+                return error_response(connection, status::Forbidden)
+            }
+            let after = config::handler_deadline(&context.config,
+                                                 &connection.request.path);
+            if let Some(after) = after {
+                connection.request.extensions.insert(Deadline(Instant::now() +
+                                                              after));
+            }
+            let listener = raw::local_addr(conn).and_then(|addr| {
+                config::listener_index(&context.config, &addr)
+            });
+            if let Some(index) = listener {
+                connection.request.extensions.insert(Listener(index));
+            }
+            if !context.proxies.is_empty() {
+                let headers = request_headers(conn).iter().filter_map(|h| {
+                    match (h.name(), h.value()) {
+                        (Some(name), Some(value)) => Some((name, value)),
+                        _ => None,
+                    }
+                }).collect::<Vec<_>>();
+                if let Some(forwarded) = proxy::resolve(&context.proxies, peer,
+                                                        &headers) {
+                    connection.request.extensions.insert(forwarded);
+                }
+            }
+            #[cfg(feature = "secure-cookies")]
+            {
+                if let Some(key) = config::cookie_key(&context.config) {
+                    connection.request.extensions.insert(key);
+                }
+            }
+            #[cfg(feature = "metrics")]
+            connection.request.extensions.insert(Stats(context.stats.clone()));
+
+            // civetweb only decodes a plain `Transfer-Encoding: chunked` and
+            // gives `Content-Length` precedence when both are present, so
+            // refuse anything it would hand to the handler undecoded.
+            if let Some(encoding) = get_header(conn, "Transfer-Encoding") {
+                let status = if !encoding.trim().eq_ignore_ascii_case("chunked") {
+                    Some(status::NotImplemented)
+                } else if get_header(conn, "Content-Length").is_some() {
+                    Some(status::BadRequest)
+                } else {
+                    None
+                };
+                if let Some(status) = status {
+                    raw::force_close(conn);
+                    return error_response(connection, status)
+                }
+            }
+
+            let declared = get_header(conn, "Content-Length")
+                .and_then(|s| s.parse::<u64>().ok());
+            match (declared, connection.request.body_limit) {
+                (Some(len), Some(limit)) if len > limit => {
+                    // Don't bother the handler with a body we won't accept.
+                    raw::force_close(conn);
+                    return error_response(connection,
+                                          status::RequestEntityTooLarge)
+                }
+                _ => {}
+            }
+
+            let _active = Active::enter(&context.stats.active);
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                respond(connection, &context.handler)
+            }));
+
+            let result = match result {
+                Ok(result) => result,
+                Err(payload) => {
+                    let info = &connection.request.request_info;
+                    error!("panic while handling {} {} ({}): {}",
+                           info.method().unwrap_or("-"),
+                           info.url().unwrap_or("-"),
+                           connection.request.request_id(),
+                           panic_message(&payload));
+                    Err(())
+                }
+            };
+
+            if connection.request.expect_continue {
+                // The client never got a 100 Continue, so it may or may not
+                // send the body; don't try to read another request after it.
+                raw::force_close(connection.request.conn);
+            }
 
-            fn err<W: Write>(writer: &mut W) {
-                let _ = writeln!(writer, "HTTP/1.1 500 Internal Server Error");
+            if result.is_err() {
+                if connection.written {
+                    // Part of the response is already on the wire, so the
+                    // best we can do is make sure the client doesn't try to
+                    // reuse the connection after the truncated response.
+                    raw::force_close(connection.request.conn);
+                } else if connection.request.body_too_large() {
+                    raw::force_close(connection.request.conn);
+                    let _ = error_response(connection,
+                                           status::RequestEntityTooLarge);
+                } else if connection.request.past_deadline() {
+                    raw::force_close(connection.request.conn);
+                    let _ = error_response(connection,
+                                           status::GatewayTimeout);
+                } else {
+                    let _ = error_response(connection,
+                                           status::InternalServerError);
+                }
+            }
+
+            // civetweb throws away whatever the handler left of the body
+            // before reading the next request, but not the trailers after a
+            // chunked one, so finish reading it here.
+            if !connection.request.expect_continue &&
+               !connection.request.body_too_large() &&
+               get_header(connection.request.conn, "Transfer-Encoding").is_some() &&
+               io::copy(&mut connection.request, &mut io::sink()).is_err() {
+                raw::force_close(connection.request.conn);
             }
 
+            result
+        }
+
+        fn error_response(connection: &mut Connection,
+                          status: status::StatusCode) -> Result<(), ()> {
+            let (code, reason) = status.to_code();
+            connection.status = Some(code);
+            let id = connection.request.request_id().to_string();
+            write!(connection, "HTTP/1.1 {} {}\r\n\
+                                {}: {}\r\n\
+                                Content-Length: 0\r\n\
+                                \r\n", code, reason,
+                   request_id::HEADER, id).map_err(|_| ())
+        }
+
+        fn respond(connection: &mut Connection,
+                   handler: &Box<Handler + 'static + Sync>)
+                   -> Result<(), ()> {
+            let response = handler.call(&mut connection.request);
+
             let conduit::Response { status, headers, mut body } = match response {
                 Ok(r) => r,
-                Err(_) => return Err(err(&mut writer)),
+                Err(e) => {
+                    let info = &connection.request.request_info;
+                    error!("error while handling {} {} ({}): {}",
+                           info.method().unwrap_or("-"),
+                           info.url().unwrap_or("-"),
+                           connection.request.request_id(), e);
+                    return Err(())
+                }
             };
+            if connection.request.past_deadline() {
+                let info = &connection.request.request_info;
+                warn!("handler for {} {} ({}) ran past its deadline",
+                      info.method().unwrap_or("-"),
+                      info.url().unwrap_or("-"),
+                      connection.request.request_id());
+                return Err(())
+            }
+
+            let mut writer = BufWriter::new(connection);
             let (code, string) = status;
+            writer.get_mut().status = Some(code);
             try!(write!(&mut writer, "HTTP/1.1 {} {}\r\n", code, string).map_err(|_| ()));
 
             for (key, value) in headers.iter() {
@@ -254,16 +849,152 @@ impl Server {
                     try!(write!(&mut writer, "{}: {}\r\n", *key, *header).map_err(|_| ()));
                 }
             }
+            if !headers.keys().any(|k| k.eq_ignore_ascii_case(request_id::HEADER)) {
+                let id = writer.get_ref().request.request_id().to_string();
+                try!(write!(&mut writer, "{}: {}\r\n", request_id::HEADER, id)
+                         .map_err(|_| ()));
+            }
 
             try!(write!(&mut writer, "\r\n").map_err(|_| ()));
             try!(body.write_body(&mut writer).map_err(|_| ()));
+            try!(writer.flush().map_err(|_| ()));
 
             Ok(())
         }
 
-        let handler = Box::new(handler);
-        let raw_callback = raw::ServerCallback::new(internal_handler, handler);
-        Ok(Server(try!(raw::Server::start(options, raw_callback))))
+        fn panic_message(payload: &Box<Any + Send>) -> &str {
+            if let Some(s) = payload.downcast_ref::<&'static str>() {
+                s
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                &s[..]
+            } else {
+                "Box<Any>"
+            }
+        }
+
+        let mut proxies = Vec::new();
+        for cidr in config::trusted_proxies(&options) {
+            match cidr.parse() {
+                Ok(cidr) => proxies.push(cidr),
+                Err(()) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("invalid trusted proxy `{}`",
+                                                      cidr)))
+                }
+            }
+        }
+
+        if let Some(cidr) = config::invalid_access_rule(&options) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid access control rule `{}`",
+                                              cidr)))
+        }
+        if let Some(rule) = config::invalid_throttle_rule(&options) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid throttle rule `{}`",
+                                              rule)))
+        }
+
+        #[cfg(feature = "tls")]
+        fn with_tls(callback: raw::ServerCallback<ServerContext>)
+                    -> raw::ServerCallback<ServerContext> {
+            fn init_ssl(ssl_ctx: *mut libc::c_void,
+                        context: &ServerContext) -> Result<(), ()> {
+                ssl::init(ssl_ctx, &context.certificates).map_err(|e| {
+                    error!("failed to set up TLS: {}", e);
+                })
+            }
+            callback.init_ssl(init_ssl)
+        }
+
+        #[cfg(not(feature = "tls"))]
+        fn with_tls(callback: raw::ServerCallback<ServerContext>)
+                    -> raw::ServerCallback<ServerContext> {
+            callback
+        }
+
+        fn count_shed(context: &ServerContext) {
+            context.stats.shed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        #[cfg(feature = "tls")]
+        let certificates = try!(ssl::Certificates::new(
+            config::tls_hosts(&options), config::client_verification(&options)));
+
+        let (_a, ptrs) = config::config_to_options(&options);
+        let uses_tls = config::uses_tls(&options);
+        let threads = config::threads(&options);
+        let backlog = config::listen_backlog(&options);
+        let queue = config::connection_queue(&options);
+        let shed_load = config::shed_load(&options);
+        let context = ServerContext {
+            handler: Box::new(handler),
+            config: options,
+            proxies: proxies,
+            stats: Arc::new(stats::Recorder::new(threads)),
+            request_ids: request_id::Generator::new(),
+            #[cfg(feature = "tls")]
+            certificates: Arc::new(certificates),
+        };
+        let raw_callback = raw::ServerCallback::new(internal_handler, context);
+        let raw_callback = if uses_tls {
+            with_tls(raw_callback)
+        } else {
+            raw_callback
+        };
+        let raw_callback = if shed_load.is_some() {
+            raw_callback.queue_full(count_shed)
+        } else {
+            raw_callback
+        };
+        let mut server = try!(raw::Server::start(&ptrs, raw_callback));
+        if let Some(backlog) = backlog {
+            try!(server.set_listen_backlog(backlog));
+        }
+        if queue.is_some() || shed_load.is_some() {
+            // civetweb answers these itself, before a worker or the request
+            // is involved, so the reply is as plain as it gets.
+            let reply = shed_load.map(|retry_after| {
+                CString::new(format!("HTTP/1.1 503 Service Unavailable\r\n\
+                                      Retry-After: {}\r\n\
+                                      Content-Length: 0\r\n\
+                                      Connection: close\r\n\
+                                      \r\n", retry_after.as_secs())).unwrap()
+            });
+            try!(server.set_connection_queue(queue.unwrap_or(20), reply));
+        }
+        Ok(Server(server))
+    }
+
+    /// What the server has been up to since it started.
+    pub fn stats(&self) -> ServerStats {
+        self.0.param().stats.snapshot()
+    }
+
+    /// How many requests `Config::access_control` or a mount's
+    /// `access_control` has turned away, as in `stats().denied_requests`.
+    pub fn denied_requests(&self) -> u64 {
+        self.0.param().stats.denied.load(Ordering::Relaxed) as u64
+    }
+
+    /// How many connections `Config::shed_load` has turned away, as in
+    /// `stats().shed_requests`.
+    pub fn shed_requests(&self) -> u64 {
+        self.0.param().stats.shed.load(Ordering::Relaxed) as u64
+    }
+
+    /// Replaces `Config::ssl_certificate`: serves new TLS handshakes that
+    /// aren't for a `Config::tls_host` with the certificate chain in `cert`
+    /// and the private key in `key`, both PEM files. Connections that are
+    /// already open keep the certificate they were set up with.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(&self, cert: &Path, key: &Path) -> io::Result<()> {
+        let context = self.0.param();
+        if !config::uses_tls(&context.config) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the server is not set up for TLS"))
+        }
+        context.certificates.reload(cert, key)
     }
 }
 
@@ -278,16 +1009,25 @@ fn request_info<'a>(connection: &'a raw::Connection)
 
 #[cfg(test)]
 mod test {
+    use std::cmp;
     use std::collections::HashMap;
     use std::error::Error;
+    use std::fs::File;
     use std::io::prelude::*;
     use std::io::{self, Cursor};
     use std::net::{SocketAddr, TcpStream, SocketAddrV4, Ipv4Addr};
-    use std::sync::Mutex;
+    use std::sync::{Mutex, MutexGuard, Once};
     use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
-    use std::sync::mpsc::{channel, Sender};
-    use super::{Server, Config, response};
-    use conduit::{Request, Response, Handler};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use log;
+    use super::{Server, Config, Mount, AccessControl, Throttle,
+                CivetRequestExt, VirtualHosts, auth, form, response};
+    use super::limit::RateLimit;
+    use super::auth::PasswordFile;
+    use super::split_host;
+    use conduit::{Request, Response, Handler, Host, Method, Scheme};
 
     fn noop(_: &mut Request) -> Result<Response, io::Error> { unreachable!() }
 
@@ -433,4 +1173,1756 @@ Foo: bar\r
         assert!(response.contains("500 Internal"),
                 "not a failing response: {}", response);
     }
+
+    #[test]
+    fn panicking_handler_sends_complete_500() {
+        struct Foo;
+        impl Handler for Foo {
+            fn call(&self, _req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                panic!()
+            }
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut config = cfg(port);
+        config.trust_request_id(true);
+        let _s = Server::start(config, Foo);
+        let response = request(addr, "\r
+GET / HTTP/1.1\r
+X-Request-Id: 1\r
+\r
+");
+        assert_eq!(response, "HTTP/1.1 500 Internal Server Error\r\n\
+                              X-Request-Id: 1\r\n\
+                              Content-Length: 0\r\n\
+                              \r\n");
+    }
+
+    #[test]
+    fn panic_after_headers_closes_connection() {
+        struct Explode(usize);
+        impl Read for Explode {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    panic!("body exploded")
+                }
+                let n = cmp::min(self.0, buf.len());
+                for b in buf[..n].iter_mut() { *b = b'a'; }
+                self.0 -= n;
+                Ok(n)
+            }
+        }
+
+        struct Foo;
+        impl Handler for Foo {
+            fn call(&self, _req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                Ok(response(200, HashMap::new(), Explode(64 * 1024)))
+            }
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut cfg = cfg(port);
+        cfg.keep_alive(true);
+        let _s = Server::start(cfg, Foo);
+
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        s.write_all(b"GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        let mut ret = Vec::new();
+        s.read_to_end(&mut ret).unwrap();
+        assert!(ret.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    struct TestLogger(Mutex<Vec<String>>);
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool { true }
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    /// What has been logged so far by any test. There can only be one
+    /// logger, so tests that look at the log share this one, installed by
+    /// whichever calls it first.
+    fn logged() -> MutexGuard<'static, Vec<String>> {
+        static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Error);
+        });
+        LOGGER.0.lock().unwrap()
+    }
+
+    #[test]
+    fn panic_is_logged() {
+        // Install the logger before there is anything to log.
+        drop(logged());
+
+        struct Foo;
+        impl Handler for Foo {
+            fn call(&self, _req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                panic!("kaboom")
+            }
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Foo);
+        request(addr, "\r
+GET /explode HTTP/1.1\r
+\r
+");
+        let logged = logged();
+        assert!(logged.iter().any(|l| l.contains("/explode") && l.contains("kaboom")),
+                "panic not logged: {:?}", *logged);
+    }
+
+    #[test]
+    fn oversized_content_length_is_413() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut cfg = cfg(port);
+        cfg.max_request_body(10);
+        let _s = Server::start(cfg, noop);
+        let response = request(addr, "\r
+POST / HTTP/1.1\r
+Content-Length: 11\r
+\r
+hello world");
+        assert!(response.starts_with("HTTP/1.1 413 "),
+                "not a 413 response: {}", response);
+    }
+
+    fn echo(req: &mut Request) -> io::Result<Response> {
+        let mut body = Vec::new();
+        try!(req.body().read_to_end(&mut body));
+        Ok(response(200, HashMap::new(), Cursor::new(body)))
+    }
+
+    #[test]
+    fn oversized_chunked_body_is_413() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut cfg = cfg(port);
+        cfg.max_request_body(10);
+        let _s = Server::start(cfg, echo);
+        let response = request(addr, "\r
+POST / HTTP/1.1\r
+Transfer-Encoding: chunked\r
+\r
+6\r
+hello \r
+5\r
+world\r
+0\r
+\r
+");
+        assert!(response.starts_with("HTTP/1.1 413 "),
+                "not a 413 response: {}", response);
+    }
+
+    #[test]
+    fn mount_overrides_body_limit() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut big = Mount::new();
+        big.max_request_body(100);
+        let mut cfg = cfg(port);
+        cfg.max_request_body(10).mount("/big", big);
+        let _s = Server::start(cfg, echo);
+        let response = request(addr, "\r
+POST /big/upload HTTP/1.1\r
+Content-Length: 11\r
+\r
+hello world");
+        assert!(response.starts_with("HTTP/1.1 200 "),
+                "not a 200 response: {}", response);
+        assert!(response.ends_with("hello world"));
+    }
+
+    fn read_head(s: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            assert_eq!(s.read(&mut byte).unwrap(), 1);
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn continue_sent_when_body_is_read() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), echo);
+
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.write_all(b"POST / HTTP/1.1\r\n\
+                      Content-Length: 5\r\n\
+                      Expect: 100-continue\r\n\
+                      \r\n").unwrap();
+        assert_eq!(read_head(&mut s), "HTTP/1.1 100 Continue\r\n\r\n");
+        s.write_all(b"hello").unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "),
+                "not a 200 response: {}", response);
+        assert!(response.ends_with("hello"));
+    }
+
+    #[test]
+    fn no_continue_when_handler_answers_first() {
+        fn reject(_req: &mut Request) -> io::Result<Response> {
+            Ok(response(401, HashMap::new(), Cursor::new(vec![])))
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), reject);
+
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        s.write_all(b"POST / HTTP/1.1\r\n\
+                      Content-Length: 5\r\n\
+                      Expect: 100-continue\r\n\
+                      \r\n").unwrap();
+        let head = read_head(&mut s);
+        assert!(head.starts_with("HTTP/1.1 401 "),
+                "not a 401 response: {}", head);
+    }
+
+    fn chunked(body: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for chunk in body.chunks(chunk_size) {
+            write!(ret, "{:X}\r\n", chunk.len()).unwrap();
+            ret.extend_from_slice(chunk);
+            ret.extend_from_slice(b"\r\n");
+        }
+        ret.extend_from_slice(b"0\r\n\r\n");
+        ret
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        fn sized_echo(req: &mut Request) -> io::Result<Response> {
+            let mut body = Vec::new();
+            try!(req.body().read_to_end(&mut body));
+            let mut headers = HashMap::new();
+            headers.insert("Content-Length".to_string(),
+                           vec![body.len().to_string()]);
+            Ok(response(200, headers, Cursor::new(body)))
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut cfg = cfg(port);
+        cfg.keep_alive(true);
+        let _s = Server::start(cfg, sized_echo);
+
+        // All on one connection, so a chunk decoder that leaves bytes behind
+        // shows up as a garbled response to the next upload.
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let sizes = [(0, 1), (1, 1), (10, 3), (1000, 1000), (70000, 4096),
+                     (200000, 65536)];
+        for &(len, chunk_size) in sizes.iter() {
+            let body = (0..len).map(|i| b'a' + (i % 26) as u8)
+                               .collect::<Vec<u8>>();
+            s.write_all(b"POST / HTTP/1.1\r\n\
+                          Transfer-Encoding: chunked\r\n\
+                          \r\n").unwrap();
+            s.write_all(&chunked(&body, chunk_size)).unwrap();
+            let head = read_head(&mut s);
+            assert!(head.starts_with("HTTP/1.1 200 "),
+                    "not a 200 response: {}", head);
+            let mut echoed = vec![0; len];
+            s.read_exact(&mut echoed).unwrap();
+            assert!(echoed == body,
+                    "body of {} bytes in {} byte chunks mangled", len,
+                    chunk_size);
+        }
+    }
+
+    #[test]
+    fn chunked_body_trailers() {
+        fn trailers(req: &mut Request) -> io::Result<Response> {
+            let mut body = String::new();
+            if req.path() == "/read" {
+                try!(req.body().read_to_string(&mut body));
+                for &(ref name, ref value) in req.trailers().unwrap_or(&[]) {
+                    body.push_str(&format!("{}={};", name, value));
+                }
+            }
+            let mut headers = HashMap::new();
+            headers.insert("Content-Length".to_string(),
+                           vec![body.len().to_string()]);
+            Ok(response(200, headers, Cursor::new(body)))
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut cfg = cfg(port);
+        cfg.keep_alive(true);
+        let _s = Server::start(cfg, trailers);
+
+        // Trailers left on the connection would be read as the start of the
+        // next request, whether or not the handler read the body.
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let uploads: [(&str, &[u8], &str); 4] = [
+            ("/read", b"hello\r\n0\r\nX-Sum: 1234\r\nX-Note:  done \r\n\r\n",
+             "helloX-Sum=1234;X-Note=done;"),
+            ("/skip", b"hello\r\n0\r\nX-Sum: 1234\r\n\r\n", ""),
+            ("/read", b"hello\r\n0\r\n\r\n", "hello"),
+            ("/skip", b"hello\r\n0\r\n\r\n", ""),
+        ];
+        for &(path, body, expected) in uploads.iter() {
+            write!(s, "POST {} HTTP/1.1\r\n\
+                       Transfer-Encoding: chunked\r\n\
+                       \r\n\
+                       5\r\n", path).unwrap();
+            s.write_all(body).unwrap();
+            let head = read_head(&mut s);
+            assert!(head.starts_with("HTTP/1.1 200 "),
+                    "not a 200 response: {}", head);
+            let mut echoed = vec![0; expected.len()];
+            s.read_exact(&mut echoed).unwrap();
+            assert_eq!(String::from_utf8(echoed).unwrap(), expected);
+        }
+
+        let response = request(addr, "\r
+POST /read HTTP/1.1\r
+Transfer-Encoding: chunked\r
+\r
+0\r
+Not a field\r
+\r
+");
+        assert!(response.starts_with("HTTP/1.1 500 "),
+                "not a 500 response: {}", response);
+    }
+
+    #[test]
+    fn chunked_with_content_length_is_400() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), noop);
+        let response = request(addr, "\r
+POST / HTTP/1.1\r
+Transfer-Encoding: chunked\r
+Content-Length: 5\r
+\r
+0\r
+\r
+");
+        assert!(response.starts_with("HTTP/1.1 400 "),
+                "not a 400 response: {}", response);
+    }
+
+    #[test]
+    fn unknown_transfer_encoding_is_501() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), noop);
+        let response = request(addr, "\r
+POST / HTTP/1.1\r
+Transfer-Encoding: gzip, chunked\r
+\r
+0\r
+\r
+");
+        assert!(response.starts_with("HTTP/1.1 501 "),
+                "not a 501 response: {}", response);
+    }
+
+    #[test]
+    fn parses_urlencoded_forms() {
+        struct Foo(Mutex<Sender<Vec<(String, String)>>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let form = form::parse(req).unwrap();
+                self.0.lock().unwrap().send(form.fields).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Foo(Mutex::new(tx)));
+
+        request(addr, "\r
+POST / HTTP/1.1\r
+Content-Type: application/x-www-form-urlencoded\r
+Content-Length: 29\r
+\r
+a=1&b=hello+world&a=%C3%A9%21");
+        assert_eq!(rx.recv().unwrap(),
+                   vec![("a".to_string(), "1".to_string()),
+                        ("b".to_string(), "hello world".to_string()),
+                        ("a".to_string(), "\u{e9}!".to_string())]);
+
+        request(addr, "\r
+GET /?x=y HTTP/1.1\r
+\r
+");
+        assert_eq!(rx.recv().unwrap(), vec![("x".to_string(), "y".to_string())]);
+    }
+
+    #[test]
+    fn parses_multipart_forms() {
+        struct Foo(Mutex<Sender<(Option<String>, String, Vec<u8>)>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let form = form::parse(req).unwrap();
+                let file = form.file("upload").unwrap();
+                let mut contents = Vec::new();
+                File::open(file.path()).unwrap().read_to_end(&mut contents).unwrap();
+                assert_eq!(file.size, contents.len() as u64);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = ::std::fs::metadata(file.path()).unwrap().permissions().mode();
+                    assert_eq!(mode & 0o777, 0o600);
+                }
+                let msg = (form.get("title").map(|s| s.to_string()),
+                           file.filename.clone(), contents);
+                self.0.lock().unwrap().send(msg).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Foo(Mutex::new(tx)));
+
+        let contents = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut body = Vec::new();
+        body.extend_from_slice(b"--XyZ\r\n\
+                                 Content-Disposition: form-data; name=\"title\"\r\n\
+                                 \r\n\
+                                 Holiday\r\n\
+                                 --XyZ\r\n\
+                                 Content-Disposition: form-data; name=\"upload\"; \
+                                 filename=\"beach.bin\"\r\n\
+                                 Content-Type: application/octet-stream\r\n\
+                                 \r\n");
+        body.extend_from_slice(&contents);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+        let mut s = TcpStream::connect(&addr).unwrap();
+        write!(s, "POST / HTTP/1.1\r\n\
+                   Content-Type: multipart/form-data; boundary=XyZ\r\n\
+                   Content-Length: {}\r\n\
+                   \r\n", body.len()).unwrap();
+        s.write_all(&body).unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "),
+                "not a 200 response: {}", response);
+
+        let (title, filename, uploaded) = rx.recv().unwrap();
+        assert_eq!(title, Some("Holiday".to_string()));
+        assert_eq!(filename, "beach.bin");
+        assert!(uploaded == contents);
+
+        // civetweb reads the body through the request, so a chunked one is
+        // held to the body limit as it arrives, and its bytes are counted.
+        fn limited(req: &mut Request) -> io::Result<Response> {
+            let form = try!(form::parse(req));
+            assert_eq!(form.get("TITLE"), Some("Holiday"));
+            assert!(form.file("Upload").is_some());
+            Ok(::response(200, HashMap::new(), Cursor::new(vec![])))
+        }
+        for &(limit, status) in &[(100000, "200"), (1000, "413")] {
+            let addr = SocketAddr::V4(SocketAddrV4::new(ip, self::port()));
+            let mut config = cfg(addr.port());
+            config.max_request_body(limit);
+            let server = Server::start(config, limited).unwrap();
+            let mut s = TcpStream::connect(&addr).unwrap();
+            s.write_all(b"POST / HTTP/1.1\r\n\
+                          Content-Type: multipart/form-data; boundary=XyZ\r\n\
+                          Transfer-Encoding: chunked\r\n\
+                          \r\n").unwrap();
+            let _ = s.write_all(&chunked(&body, 1000));
+            let mut response = String::new();
+            s.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {} ", status)),
+                    "not a {} response: {}", status, response);
+            let read = server.stats().bytes_read;
+            if status == "200" {
+                assert_eq!(read, body.len() as u64);
+            } else {
+                assert!(read > limit && read <= limit + 1024, "{}", read);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn form_upload_paths_must_be_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::PathBuf;
+
+        struct ToFile;
+        impl form::Visitor for ToFile {
+            fn field(&mut self, _field: &form::Field) -> form::Storage {
+                let name = OsStr::from_bytes(b"civet-\xff.bin");
+                form::Storage::File(::std::env::temp_dir().join(PathBuf::from(name)))
+            }
+        }
+        fn upload(req: &mut Request) -> io::Result<Response> {
+            let err = form::visit(req, &mut ToFile).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            Ok(::response(200, HashMap::new(), Cursor::new(vec![])))
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), upload);
+        let response = request(addr, "\r
+POST / HTTP/1.1\r
+Content-Type: multipart/form-data; boundary=XyZ\r
+Content-Length: 77\r
+\r
+--XyZ\r
+Content-Disposition: form-data; name=\"f\"; filename=\"a\"\r
+\r
+x\r
+--XyZ--\r
+");
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    }
+
+    #[test]
+    fn query_and_cookies() {
+        struct Foo(Mutex<Sender<(Option<String>, Option<String>, Option<String>)>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let msg = (req.query().get("q").map(|s| s.to_string()),
+                           req.cookies().get("a").map(|s| s.to_string()),
+                           req.cookies().get("b").map(|s| s.to_string()));
+                self.0.lock().unwrap().send(msg).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Foo(Mutex::new(tx)));
+        request(addr, "\r
+GET /search?q=rust+web HTTP/1.1\r
+Cookie: a=1\r
+Cookie: b=2\r
+\r
+");
+        assert_eq!(rx.recv().unwrap(),
+                   (Some("rust web".to_string()), Some("1".to_string()),
+                    Some("2".to_string())));
+    }
+
+    #[test]
+    fn raw_request_target() {
+        struct Foo(Mutex<Sender<(String, Option<String>, Option<String>,
+                                 Option<String>)>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let msg = (req.path().to_string(), req.request_uri(),
+                           req.local_uri(), req.remote_user());
+                self.0.lock().unwrap().send(msg).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut config = cfg(port);
+        config.decode_url(false);
+        let _s = Server::start(config, Foo(Mutex::new(tx.clone())));
+        let target = "GET /a%20b//x/../c%2F%2E%2E/d?x=%20 HTTP/1.1\r\n\r\n";
+        request(addr, target);
+        // civetweb drops `..` rather than resolving it, but the target keeps
+        // it.
+        assert_eq!(rx.recv().unwrap(),
+                   ("/a b/x/c/d".to_string(),
+                    Some("/a%20b//x/../c%2F%2E%2E/d?x=%20".to_string()),
+                    Some("/a%20b/x/c%2F%2E%2E/d".to_string()), None));
+        // civetweb only takes absolute targets for its authentication
+        // domain, which defaults to mydomain.com.
+        let absolute = format!("http://mydomain.com:{}/a//b?c", port);
+        request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", absolute));
+        assert_eq!(rx.recv().unwrap().1, Some(absolute));
+
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, self::port()));
+        let _s = Server::start(cfg(addr.port()), Foo(Mutex::new(tx)));
+        request(addr, target);
+        assert_eq!(rx.recv().unwrap(),
+                   ("/a b/x/c/d".to_string(), None,
+                    Some("/a b/x/c/d".to_string()), None));
+    }
+
+    #[test]
+    fn host_headers() {
+        assert_eq!(split_host("example.com"), Some(("example.com", None)));
+        assert_eq!(split_host("example.com:8080"),
+                   Some(("example.com", Some(8080))));
+        assert_eq!(split_host("127.0.0.1:80"), Some(("127.0.0.1", Some(80))));
+        assert_eq!(split_host("[::1]"), Some(("::1", None)));
+        assert_eq!(split_host("[::1]:8080"), Some(("::1", Some(8080))));
+        assert_eq!(split_host("[::1"), None);
+        assert_eq!(split_host("[::1]8080"), None);
+        assert_eq!(split_host("example.com:http"), None);
+        assert_eq!(split_host(":80"), None);
+        assert_eq!(split_host(""), None);
+    }
+
+    #[test]
+    fn host_is_resolved() {
+        struct Foo(Mutex<Sender<(String, u16)>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let host = match req.host() {
+                    Host::Name(name) => name.to_string(),
+                    Host::Socket(addr) => addr.to_string(),
+                };
+                self.0.lock().unwrap().send((host, req.host_port())).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Foo(Mutex::new(tx)));
+
+        request(addr, "GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), ("example.com".to_string(), 8080));
+        request(addr, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), ("example.com".to_string(), 80));
+        request(addr, "GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), ("[::1]:8080".to_string(), 8080));
+        request(addr, "GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), ("[::1]:80".to_string(), 80));
+        request(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), (addr.to_string(), port));
+    }
+
+    #[test]
+    fn listener_is_reported() {
+        struct Foo(Mutex<Sender<(Option<SocketAddr>, Option<usize>)>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let msg = (req.local_addr(), req.listener());
+                self.0.lock().unwrap().send(msg).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let (public, admin) = (port(), port());
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let public = SocketAddr::V4(SocketAddrV4::new(ip, public));
+        let admin = SocketAddr::V4(SocketAddrV4::new(ip, admin));
+        let mut cfg = cfg(public.port());
+        cfg.listen(admin);
+        let _s = Server::start(cfg, Foo(Mutex::new(tx)));
+
+        request(public, "GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), (Some(public), Some(0)));
+        request(admin, "GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(rx.recv().unwrap(), (Some(admin), Some(1)));
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        struct Foo(Mutex<Sender<(String, bool, String)>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let host = match req.host() {
+                    Host::Name(name) => name.to_string(),
+                    Host::Socket(addr) => addr.to_string(),
+                };
+                let msg = (req.remote_addr().ip().to_string(),
+                           req.scheme() == Scheme::Https, host);
+                self.0.lock().unwrap().send(msg).unwrap();
+                let mut headers = HashMap::new();
+                headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+                Ok(response(200, headers, Cursor::new(vec![])))
+            }
+        }
+        let forwarded = "GET / HTTP/1.1\r
+Host: internal\r
+X-Forwarded-For: 203.0.113.9\r
+X-Forwarded-Proto: https\r
+X-Forwarded-Host: example.com\r
+\r
+";
+
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let (tx, rx) = channel();
+        let (trusting, untrusting) = (port(), port());
+        let mut config = cfg(trusting);
+        config.trusted_proxies(&["127.0.0.0/8"]);
+        let _s1 = Server::start(config, Foo(Mutex::new(tx.clone())));
+        let mut config = cfg(untrusting);
+        config.trusted_proxies(&["10.0.0.0/8"]);
+        let _s2 = Server::start(config, Foo(Mutex::new(tx)));
+
+        request(SocketAddr::V4(SocketAddrV4::new(ip, trusting)), forwarded);
+        assert_eq!(rx.recv().unwrap(),
+                   ("203.0.113.9".to_string(), true, "example.com".to_string()));
+        request(SocketAddr::V4(SocketAddrV4::new(ip, untrusting)), forwarded);
+        assert_eq!(rx.recv().unwrap(),
+                   ("127.0.0.1".to_string(), false, "internal".to_string()));
+
+        // A shorter request on the same connection mustn't pick up the
+        // forwarding headers the one before it left behind.
+        let (tx, rx) = channel();
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let mut config = cfg(addr.port());
+        config.trusted_proxies(&["127.0.0.0/8"]);
+        config.keep_alive(true);
+        let _s3 = Server::start(config, Foo(Mutex::new(tx)));
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        s.write_all(forwarded.as_bytes()).unwrap();
+        assert!(read_head(&mut s).starts_with("HTTP/1.1 200 "));
+        assert_eq!(rx.recv().unwrap(),
+                   ("203.0.113.9".to_string(), true, "example.com".to_string()));
+        s.write_all(b"GET / HTTP/1.1\r\nHost: internal\r\n\r\n").unwrap();
+        assert!(read_head(&mut s).starts_with("HTTP/1.1 200 "));
+        assert_eq!(rx.recv().unwrap(),
+                   ("127.0.0.1".to_string(), false, "internal".to_string()));
+
+        let mut config = cfg(port());
+        config.trusted_proxies(&["10.0.0.0/99"]);
+        assert!(Server::start(config, noop).is_err());
+    }
+
+    struct Site(&'static str);
+    impl Handler for Site {
+        fn call(&self, _req: &mut Request) -> Result<Response, Box<Error+Send>> {
+            let body = self.0.as_bytes().to_vec();
+            Ok(response(200, HashMap::new(), Cursor::new(body)))
+        }
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let mut hosts = VirtualHosts::new(Site("default"));
+        hosts.host("example.org", Site("example"))
+             .host("www.example.org", Site("www"));
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), hosts).unwrap();
+
+        let site = |host: &str| {
+            let req = format!("GET / HTTP/1.0\r\n{}\r\n", host);
+            let ret = request(addr, &req);
+            ret[ret.find("\r\n\r\n").unwrap() + 4..].to_string()
+        };
+        assert_eq!(site("Host: example.org\r\n"), "example");
+        assert_eq!(site("Host: WWW.Example.org.:8080\r\n"), "www");
+        assert_eq!(site("Host: example.com\r\n"), "default");
+        assert_eq!(site("Host: 127.0.0.1\r\n"), "default");
+        assert_eq!(site(""), "default");
+    }
+
+    #[test]
+    fn access_control() {
+        let mut acl = AccessControl::new();
+        acl.deny("0.0.0.0/0").allow("10.0.0.0/8").deny("10.1.0.0/16")
+           .allow("fd00::/8");
+        assert!(acl.allows("10.2.3.4".parse().unwrap()));
+        assert!(!acl.allows("10.1.3.4".parse().unwrap()));
+        assert!(!acl.allows("192.168.0.1".parse().unwrap()));
+        assert!(acl.allows("fd00::1".parse().unwrap()));
+        assert!(!acl.allows("::1".parse().unwrap()));
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut local = AccessControl::new();
+        local.allow("127.0.0.1");
+        let mut public = Mount::new();
+        public.access_control(local);
+        let mut remote = AccessControl::new();
+        remote.allow("0.0.0.0/0").allow("::/0").deny("127.0.0.0/8");
+        let mut config = cfg(port);
+        config.access_control(remote).mount("/public", public);
+        let s = Server::start(config, Site("ok")).unwrap();
+
+        let ret = request(addr, "GET /private HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 403 "), "{}", ret);
+        let ret = request(addr, "GET /public/a HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        let ret = request(addr, "GET /public HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        let ret = request(addr, "GET /public-secrets HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 403 "), "{}", ret);
+        assert_eq!(s.denied_requests(), 2);
+        assert_eq!(s.stats().denied_requests, 2);
+
+        let mut bad = AccessControl::new();
+        bad.allow("10.0.0.0/8").deny("localhost");
+        let mut mount = Mount::new();
+        mount.access_control(bad);
+        let mut config = Config::new();
+        config.mount("/", mount);
+        assert!(Server::start(config, noop).is_err());
+    }
+
+    #[test]
+    fn throttle() {
+        struct Big;
+        impl Handler for Big {
+            fn call(&self, _req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                Ok(response(200, HashMap::new(), Cursor::new(vec![b'x'; 3000])))
+            }
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut throttle = Throttle::new();
+        throttle.all(0).network("10.0.0.0/8", 1).path("/slow/", 1024);
+        let mut config = cfg(port);
+        config.throttle(throttle);
+        let _s = Server::start(config, Big).unwrap();
+
+        let timed = |path: &str| {
+            let start = Instant::now();
+            let ret = request(addr, &format!("GET {} HTTP/1.0\r\n\r\n", path));
+            assert!(ret.ends_with(&"x".repeat(3000)), "{}", ret);
+            start.elapsed()
+        };
+        assert!(timed("/fast") < Duration::from_secs(1));
+        assert!(timed("/slow/a") >= Duration::from_secs(2));
+
+        for bad in &["fd00::/8", "::ffff:10.0.0.0/104", "nope"] {
+            let mut throttle = Throttle::new();
+            throttle.network(bad, 1);
+            let mut config = Config::new();
+            config.throttle(throttle);
+            assert!(Server::start(config, noop).is_err());
+        }
+        let mut throttle = Throttle::new();
+        throttle.path("/a,b", 1);
+        let mut config = Config::new();
+        config.throttle(throttle);
+        assert!(Server::start(config, noop).is_err());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let mut limit = RateLimit::new(2, Duration::from_secs(60), Site("ok"))
+            .unwrap();
+        limit.key(|req| {
+            req.headers().find("X-Key").map(|values| values[0].to_string())
+        });
+        let mut config = Config::new();
+        config.threads(1).listen(addr);
+        let _s = Server::start(config, limit).unwrap();
+
+        let get = |key: &str| {
+            request(addr, &format!("GET / HTTP/1.0\r\nX-Key: {}\r\n\r\n", key))
+        };
+        assert!(get("a").starts_with("HTTP/1.1 200 "));
+        assert!(get("a").starts_with("HTTP/1.1 200 "));
+        let ret = get("a");
+        assert!(ret.starts_with("HTTP/1.1 429 "), "{}", ret);
+        assert!(ret.contains("Retry-After: 30\r\n"), "{}", ret);
+        assert!(get("b").starts_with("HTTP/1.1 200 "));
+        for _ in 0..3 {
+            let ret = request(addr, "GET / HTTP/1.0\r\n\r\n");
+            assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        }
+
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let mut limit = RateLimit::new(5, Duration::from_secs(1), Site("ok"))
+            .unwrap();
+        limit.burst(1);
+        let mut config = Config::new();
+        config.threads(1).listen(addr);
+        let _s = Server::start(config, limit).unwrap();
+        let get = || request(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert!(get().starts_with("HTTP/1.1 200 "));
+        assert!(get().starts_with("HTTP/1.1 429 "));
+    }
+
+    #[test]
+    fn slow_clients_time_out() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut config = cfg(port);
+        config.request_timeout(Duration::from_millis(300))
+              .keep_alive_timeout(Duration::from_millis(100))
+              .linger_timeout(Duration::from_millis(0));
+        let _s = Server::start(config, Site("ok")).unwrap();
+
+        // Take up the only worker with half a request.
+        let mut slow = TcpStream::connect(&addr).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        let ret = request(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert!(ret.ends_with("\r\n\r\nok"), "{}", ret);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // The slow client has been hung up on.
+        slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut rest = Vec::new();
+        let _ = slow.read_to_end(&mut rest);
+        assert!(!String::from_utf8_lossy(&rest).contains("ok"));
+    }
+
+    #[test]
+    fn handler_deadline() {
+        struct Slow(Mutex<Sender<bool>>);
+        impl Handler for Slow {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                self.0.lock().unwrap().send(req.deadline().is_some()).unwrap();
+                thread::sleep(Duration::from_millis(300));
+                Ok(response(200, HashMap::new(), Cursor::new(b"late".to_vec())))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut patient = Mount::new();
+        patient.handler_deadline(Duration::from_secs(10));
+        let mut config = cfg(port);
+        config.handler_deadline(Duration::from_millis(100))
+              .mount("/patient", patient);
+        let _s = Server::start(config, Slow(Mutex::new(tx))).unwrap();
+
+        let ret = request(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 504 ") && !ret.contains("late"),
+                "{}", ret);
+        assert!(rx.recv().unwrap());
+        let ret = request(addr, "GET /patient HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+    }
+
+    #[test]
+    fn sheds_load() {
+        struct Busy(Mutex<Sender<()>>, Mutex<Receiver<()>>);
+        impl Handler for Busy {
+            fn call(&self, _req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                self.0.lock().unwrap().send(()).unwrap();
+                self.1.lock().unwrap().recv().unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (started_tx, started) = channel();
+        let (release, release_rx) = channel();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut config = cfg(port);
+        config.threads(1)
+              .connection_queue(1)
+              .shed_load(Duration::from_secs(5));
+        let s = Server::start(config, Busy(Mutex::new(started_tx),
+                                           Mutex::new(release_rx))).unwrap();
+
+        // The only worker is busy with the first connection, and the second
+        // fills the queue, so the third is turned away as it is accepted,
+        // without sending anything.
+        let busy = thread::spawn(move || request(addr, "GET / HTTP/1.0\r\n\r\n"));
+        started.recv().unwrap();
+        let mut queued = TcpStream::connect(&addr).unwrap();
+        queued.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut shed = TcpStream::connect(&addr).unwrap();
+        let mut ret = String::new();
+        shed.read_to_string(&mut ret).unwrap();
+        assert!(ret.starts_with("HTTP/1.1 503 "), "{}", ret);
+        assert!(ret.contains("Retry-After: 5\r\n"), "{}", ret);
+        assert_eq!(s.shed_requests(), 1);
+        assert_eq!(s.stats().shed_requests, 1);
+
+        release.send(()).unwrap();
+        let ret = busy.join().unwrap();
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        started.recv().unwrap();
+        release.send(()).unwrap();
+        let mut ret = String::new();
+        queued.read_to_string(&mut ret).unwrap();
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+    }
+
+    #[test]
+    fn connection_queue_out_of_range() {
+        for &queue in &[0, 1025] {
+            let mut config = cfg(port());
+            config.connection_queue(queue);
+            let err = Server::start(config, echo).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn listen_backlog() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut config = cfg(port);
+        config.listen_backlog(512);
+        let _s = Server::start(config, echo).unwrap();
+        let ret = request(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+    }
+
+    #[test]
+    fn stats() {
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let s = Server::start(cfg(port), echo).unwrap();
+
+        let stats = s.stats();
+        assert_eq!(stats.requests, 0);
+        assert_eq!(stats.threads, 1);
+
+        let mut written = 0;
+        for _ in 0..2 {
+            let ret = request(addr, "POST / HTTP/1.0\r\n\
+                                     Content-Length: 5\r\n\r\nhello");
+            assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+            written += ret.len() as u64;
+        }
+        let ret = request(addr, "GET / HTTP/1.0\r\n\
+                                 Transfer-Encoding: gzip\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 501 "), "{}", ret);
+        written += ret.len() as u64;
+
+        let stats = s.stats();
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.responses[&("POST".to_string(), 200)], 2);
+        assert_eq!(stats.status_count(501), 1);
+        assert_eq!(stats.bytes_read, 10);
+        assert_eq!(stats.bytes_written, written);
+        assert_eq!(stats.active_requests, 0);
+        assert_eq!(stats.latency.count, 3);
+        assert_eq!(stats.latency.buckets.len(), 11);
+        assert!(stats.latency.buckets.last().unwrap().1 <= 3);
+    }
+
+    #[test]
+    fn request_ids() {
+        fn id(req: &mut Request) -> io::Result<Response> {
+            let body = req.request_id().unwrap().into_bytes();
+            Ok(response(200, HashMap::new(), Cursor::new(body)))
+        }
+        fn header(ret: &str) -> &str {
+            let start = ret.find("\r\nX-Request-Id: ").unwrap() + 16;
+            &ret[start..start + ret[start..].find("\r\n").unwrap()]
+        }
+
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let trusted = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let _s = Server::start(cfg(addr.port()), id).unwrap();
+        let first = request(addr, "GET / HTTP/1.0\r\n\r\n");
+        assert!(first.ends_with(&format!("\r\n\r\n{}", header(&first))), "{}", first);
+        let second = request(addr, "GET / HTTP/1.0\r\nX-Request-Id: abc\r\n\r\n");
+        assert!(header(&second) != header(&first));
+        assert!(header(&second) != "abc");
+        let ret = request(addr, "GET / HTTP/1.0\r\n\
+                                 Transfer-Encoding: gzip\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 501 "), "{}", ret);
+        assert!(header(&ret) != header(&first));
+
+        let mut config = cfg(trusted.port());
+        config.trust_request_id(true);
+        let _s = Server::start(config, id).unwrap();
+        let ret = request(trusted, "GET / HTTP/1.0\r\nX-Request-Id: abc-123\r\n\r\n");
+        assert_eq!(header(&ret), "abc-123");
+        assert!(ret.ends_with("\r\n\r\nabc-123"), "{}", ret);
+        let ret = request(trusted, "GET / HTTP/1.0\r\nX-Request-Id: a b\r\n\r\n");
+        assert!(header(&ret) != "a b");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics::Metrics;
+
+        struct App;
+        impl Handler for App {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                if req.path() == "/metrics" {
+                    return Metrics.call(req)
+                }
+                Ok(response(404, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let mut config = cfg(port);
+        config.threads(4);
+        let _s = Server::start(config, App).unwrap();
+
+        request(addr, "GET /missing HTTP/1.0\r\n\r\n");
+        request(addr, "DELETE /missing HTTP/1.0\r\n\r\n");
+        let ret = request(addr, "GET /metrics HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        assert!(ret.contains("Content-Type: text/plain; version=0.0.4"), "{}", ret);
+        assert!(ret.contains("# TYPE civet_requests_total counter\n"), "{}", ret);
+        assert!(ret.contains("\ncivet_requests_total{method=\"GET\",status=\"404\"} 1\n"),
+                "{}", ret);
+        assert!(ret.contains("\ncivet_requests_total{method=\"DELETE\",status=\"404\"} 1\n"),
+                "{}", ret);
+        assert!(ret.contains("\ncivet_request_duration_seconds_bucket{le=\"0.005\"} "),
+                "{}", ret);
+        assert!(ret.contains("\ncivet_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"),
+                "{}", ret);
+        assert!(ret.contains("\ncivet_request_duration_seconds_count 2\n"), "{}", ret);
+        assert!(ret.contains("\ncivet_active_requests 1\n"), "{}", ret);
+        assert!(ret.contains("\ncivet_worker_threads 4\n"), "{}", ret);
+        assert!(ret.contains("\ncivet_worker_utilization 0.25\n"), "{}", ret);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        use conditional::{self, Conditional};
+
+        fn doc(req: &mut Request) -> io::Result<Response> {
+            let mut headers = HashMap::new();
+            match req.method() {
+                Method::Put => {
+                    if let Some(response) = conditional::check(req, Some("\"v2\""), None) {
+                        return Ok(response)
+                    }
+                    return Ok(response(204, headers, io::empty()))
+                }
+                _ if req.path() == "/missing" => {
+                    return Ok(response(404, headers, io::empty()))
+                }
+                _ => {}
+            }
+            headers.insert("ETag".to_string(), vec!["\"v2\"".to_string()]);
+            headers.insert("Last-Modified".to_string(),
+                           vec!["Sun, 06 Nov 1994 08:49:37 GMT".to_string()]);
+            headers.insert("Content-Type".to_string(), vec!["text/plain".to_string()]);
+            headers.insert("Content-Length".to_string(), vec!["4".to_string()]);
+            Ok(response(200, headers, Cursor::new(b"body".to_vec())))
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Conditional::new(doc)).unwrap();
+        let status = |method: &str, path: &str, condition: &str| {
+            let mut req = format!("{} {} HTTP/1.1\r\nContent-Length: 0\r\n", method, path);
+            if !condition.is_empty() {
+                req.push_str(condition);
+                req.push_str("\r\n");
+            }
+            req.push_str("\r\n");
+            let ret = request(addr, &req);
+            ret[9..12].to_string()
+        };
+
+        assert_eq!(status("GET", "/", ""), "200");
+        let ret = request(addr, "GET / HTTP/1.1\r\nIf-None-Match: \"v1\", W/\"v2\"\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 304 "), "{}", ret);
+        assert!(ret.contains("\r\nETag: \"v2\"\r\n"), "{}", ret);
+        assert!(!ret.contains("Content-Type"), "{}", ret);
+        assert!(!ret.contains("Content-Length"), "{}", ret);
+        assert!(ret.ends_with("\r\n\r\n"), "{}", ret);
+        assert_eq!(status("HEAD", "/", "If-None-Match: *"), "304");
+        assert_eq!(status("GET", "/", "If-None-Match: \"v1\""), "200");
+        assert_eq!(status("GET", "/", "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT"),
+                   "304");
+        assert_eq!(status("GET", "/", "If-Modified-Since: Sat, 05 Nov 1994 08:49:37 GMT"),
+                   "200");
+        assert_eq!(status("GET", "/", "If-Modified-Since: not a date"), "200");
+        assert_eq!(status("GET", "/", "If-None-Match: \"v1\"\r\n\
+                                       If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT"),
+                   "200");
+        assert_eq!(status("GET", "/", "If-Match: \"v1\""), "412");
+        assert_eq!(status("GET", "/", "If-Match: W/\"v2\""), "412");
+        assert_eq!(status("GET", "/", "If-Match: \"v1\", \"v2\""), "200");
+        assert_eq!(status("GET", "/",
+                          "If-Unmodified-Since: Sat, 05 Nov 1994 08:49:37 GMT"), "412");
+        assert_eq!(status("GET", "/missing", "If-None-Match: *"), "404");
+
+        assert_eq!(status("PUT", "/", "If-Match: \"v1\""), "412");
+        assert_eq!(status("PUT", "/", "If-Match: \"v2\""), "204");
+        assert_eq!(status("PUT", "/", "If-None-Match: *"), "412");
+        assert_eq!(status("PUT", "/", ""), "204");
+    }
+
+    #[test]
+    fn byte_ranges() {
+        use range;
+
+        fn artifact(req: &mut Request) -> io::Result<Response> {
+            let mut headers = HashMap::new();
+            headers.insert("Content-Type".to_string(), vec!["text/plain".to_string()]);
+            headers.insert("ETag".to_string(), vec!["\"a1\"".to_string()]);
+            headers.insert("Last-Modified".to_string(),
+                           vec!["Sun, 06 Nov 1994 08:49:37 GMT".to_string()]);
+            range::serve(req, headers, Cursor::new(b"0123456789abcdefghij".to_vec()))
+        }
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), artifact).unwrap();
+        let get = |headers: &str| {
+            request(addr, &format!("GET / HTTP/1.1\r\n{}\r\n", headers))
+        };
+
+        let ret = get("");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        assert!(ret.contains("\r\nAccept-Ranges: bytes\r\n"), "{}", ret);
+        assert!(ret.contains("\r\nContent-Length: 20\r\n"), "{}", ret);
+        assert!(ret.ends_with("\r\n\r\n0123456789abcdefghij"), "{}", ret);
+
+        for &(range, content_range, body) in [("0-4", "0-4", "01234"),
+                                              ("15-", "15-19", "fghij"),
+                                              ("-3", "17-19", "hij"),
+                                              ("18-100", "18-19", "ij"),
+                                              ("30-40, 2-2", "2-2", "2")].iter() {
+            let ret = get(&format!("Range: bytes={}\r\n", range));
+            assert!(ret.starts_with("HTTP/1.1 206 "), "{}", ret);
+            assert!(ret.contains(&format!("\r\nContent-Range: bytes {}/20\r\n", content_range)),
+                    "{}", ret);
+            assert!(ret.contains(&format!("\r\nContent-Length: {}\r\n", body.len())), "{}", ret);
+            assert!(ret.ends_with(&format!("\r\n\r\n{}", body)), "{}", ret);
+        }
+
+        let ret = get("Range: bytes=0-1,5-6\r\n");
+        assert!(ret.starts_with("HTTP/1.1 206 "), "{}", ret);
+        let start = ret.find("multipart/byteranges; boundary=").unwrap() + 31;
+        let boundary = &ret[start..start + ret[start..].find("\r\n").unwrap()];
+        let body = &ret[ret.find("\r\n\r\n").unwrap() + 4..];
+        assert_eq!(body, format!("\r\n--{0}\r\nContent-Type: text/plain\r\n\
+                                  Content-Range: bytes 0-1/20\r\n\r\n01\
+                                  \r\n--{0}\r\nContent-Type: text/plain\r\n\
+                                  Content-Range: bytes 5-6/20\r\n\r\n56\
+                                  \r\n--{0}--\r\n", boundary));
+        assert!(ret.contains(&format!("\r\nContent-Length: {}\r\n", body.len())), "{}", ret);
+
+        let ret = get("Range: bytes=20-\r\n");
+        assert!(ret.starts_with("HTTP/1.1 416 "), "{}", ret);
+        assert!(ret.contains("\r\nContent-Range: bytes */20\r\n"), "{}", ret);
+        let ret = get("Range: bytes=abc\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+
+        let ret = get("Range: bytes=0-1\r\nIf-Range: \"a1\"\r\n");
+        assert!(ret.starts_with("HTTP/1.1 206 "), "{}", ret);
+        let ret = get("Range: bytes=0-1\r\nIf-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n");
+        assert!(ret.starts_with("HTTP/1.1 206 "), "{}", ret);
+        let ret = get("Range: bytes=0-1\r\nIf-Range: \"a0\"\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        let ret = get("Range: bytes=0-1\r\nIf-Range: W/\"a1\"\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+
+        let ret = request(addr, "HEAD / HTTP/1.1\r\nRange: bytes=0-1\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        assert!(ret.contains("\r\nContent-Length: 20\r\n"), "{}", ret);
+        assert!(ret.ends_with("\r\n\r\n"), "{}", ret);
+    }
+
+    /// Sends `req` and splits what comes back into the head and the body.
+    #[cfg(feature = "compression")]
+    fn fetch(addr: SocketAddr, req: &str) -> (String, Vec<u8>) {
+        let mut s = TcpStream::connect(&addr).unwrap();
+        s.write_all(req.as_bytes()).unwrap();
+        let mut ret = Vec::new();
+        s.read_to_end(&mut ret).unwrap();
+        let end = ret.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = ret.split_off(end);
+        (String::from_utf8(ret).unwrap(), body)
+    }
+
+    #[cfg(feature = "compression")]
+    fn dechunk(mut body: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        loop {
+            let line = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let len = usize::from_str_radix(::std::str::from_utf8(&body[..line]).unwrap(), 16)
+                .unwrap();
+            body = &body[line + 2..];
+            if len == 0 {
+                assert_eq!(body, b"\r\n");
+                return ret
+            }
+            ret.extend_from_slice(&body[..len]);
+            assert_eq!(&body[len..len + 2], b"\r\n");
+            body = &body[len + 2..];
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression() {
+        use compress::Compress;
+        use flate2::read::{GzDecoder, ZlibDecoder};
+
+        fn text(req: &mut Request) -> io::Result<Response> {
+            let (content_type, body) = match req.path() {
+                "/small" => ("text/plain", "hello".to_string()),
+                "/image" => ("image/png", "\u{89}PNG".repeat(500)),
+                _ => ("text/plain; charset=utf-8", "hello, world\n".repeat(200)),
+            };
+            let mut headers = HashMap::new();
+            headers.insert("Content-Type".to_string(), vec![content_type.to_string()]);
+            headers.insert("Content-Length".to_string(), vec![body.len().to_string()]);
+            headers.insert("ETag".to_string(), vec!["\"v1\"".to_string()]);
+            headers.insert("Accept-Ranges".to_string(), vec!["bytes".to_string()]);
+            Ok(response(200, headers, Cursor::new(body.into_bytes())))
+        }
+
+        let expected = "hello, world\n".repeat(200).into_bytes();
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Compress::new(text)).unwrap();
+
+        let (head, body) = fetch(addr, "GET / HTTP/1.1\r\n\
+                                        Accept-Encoding: gzip, br;q=0.5\r\n\r\n");
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"), "{}", head);
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", head);
+        assert!(head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+        assert!(head.contains("\r\nETag: W/\"v1\"\r\n"), "{}", head);
+        assert!(!head.contains("Content-Length"), "{}", head);
+        assert!(!head.contains("Accept-Ranges"), "{}", head);
+        let mut decoded = Vec::new();
+        GzDecoder::new(&dechunk(&body)[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+
+        let (head, body) = fetch(addr, "GET / HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n");
+        assert!(head.contains("\r\nContent-Encoding: br\r\n"), "{}", head);
+        let mut decoded = Vec::new();
+        ::brotli::Decompressor::new(&dechunk(&body)[..], 4096)
+            .read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+
+        let (head, body) = fetch(addr, "GET / HTTP/1.0\r\nAccept-Encoding: deflate\r\n\r\n");
+        assert!(head.contains("\r\nContent-Encoding: deflate\r\n"), "{}", head);
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+
+        let (head, body) = fetch(addr, "HEAD / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"), "{}", head);
+        assert!(body.is_empty());
+
+        let (head, body) = fetch(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(!head.contains("Content-Encoding"), "{}", head);
+        assert!(head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+        assert!(head.contains("\r\nAccept-Ranges: bytes\r\n"), "{}", head);
+        assert_eq!(body, expected);
+
+        for path in ["/small", "/image"].iter() {
+            let req = format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", path);
+            let (head, _) = fetch(addr, &req);
+            assert!(!head.contains("Content-Encoding"), "{}", head);
+            assert!(!head.contains("Vary"), "{}", head);
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn precompressed() {
+        use compress::Precompressed;
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let root = ::std::env::temp_dir()
+            .join(format!("civet-precompressed-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&root);
+        ::std::fs::create_dir_all(root.join("js")).unwrap();
+        ::std::fs::write(root.join("js/app.js"), "plain").unwrap();
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"zipped").unwrap();
+        ::std::fs::write(root.join("js/app.js.gz"), gz.finish().unwrap()).unwrap();
+        ::std::fs::write(root.join("index.html"), "<p>hi</p>").unwrap();
+
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), Precompressed::new(&root)).unwrap();
+
+        let (head, body) = fetch(addr, "GET /js/app.js HTTP/1.1\r\n\
+                                        Accept-Encoding: br, gzip\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Type: application/javascript\r\n"), "{}", head);
+        assert!(head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+        let mut decoded = String::new();
+        ::flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "zipped");
+
+        let (head, body) = fetch(addr, "GET /js/app.js HTTP/1.1\r\n\
+                                        Accept-Encoding: gzip;q=0\r\n\r\n");
+        assert!(!head.contains("Content-Encoding"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 5\r\n"), "{}", head);
+        assert_eq!(body, b"plain");
+
+        let (head, body) = fetch(addr, "GET /js/app.js HTTP/1.1\r\nRange: bytes=1-3\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 206 "), "{}", head);
+        assert!(head.contains("\r\nLast-Modified: "), "{}", head);
+        assert_eq!(body, b"lai");
+
+        let (head, body) = fetch(addr, "GET /index.html HTTP/1.1\r\n\
+                                        Accept-Encoding: gzip\r\n\r\n");
+        assert!(head.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"), "{}", head);
+        assert!(!head.contains("Vary"), "{}", head);
+        assert_eq!(body, b"<p>hi</p>");
+
+        let (head, _) = fetch(addr, "GET /js HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+        let (head, _) = fetch(addr, "POST /index.html HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 405 "), "{}", head);
+        assert!(head.contains("\r\nAllow: GET, HEAD\r\n"), "{}", head);
+        ::std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    struct WhoAmI;
+    impl Handler for WhoAmI {
+        fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+            let body = req.remote_user().unwrap_or_default().into_bytes();
+            Ok(response(200, HashMap::new(), Cursor::new(body)))
+        }
+    }
+
+    fn password_file(name: &str) -> (::std::path::PathBuf, PasswordFile) {
+        let path = ::std::env::temp_dir()
+            .join(format!("civet-{}-{}.htpasswd", name, ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let file = PasswordFile::new(&path);
+        file.set_password("test", "alice", "wonderland").unwrap();
+        file.set_password("test", "bob", "builder").unwrap();
+        file.set_password("other", "carol", "singer").unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn basic_auth() {
+        let (path, users) = password_file("basic");
+        users.remove_user("test", "bob").unwrap();
+        assert!(users.set_password("test", "bob", "").is_err());
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), auth::Basic::new("test", users, WhoAmI))
+            .unwrap();
+
+        let get = |credentials: Option<&str>| {
+            let header = credentials.map(|c| format!("Authorization: Basic {}\r\n", c))
+                .unwrap_or_default();
+            request(addr, &format!("GET / HTTP/1.0\r\n{}\r\n", header))
+        };
+        // alice:wonderland
+        let ret = get(Some("YWxpY2U6d29uZGVybGFuZA=="));
+        assert!(ret.starts_with("HTTP/1.1 200 ") && ret.ends_with("\r\nalice"),
+                "{}", ret);
+        let ret = get(None);
+        assert!(ret.starts_with("HTTP/1.1 401 "), "{}", ret);
+        assert!(ret.contains("WWW-Authenticate: Basic realm=\"test\""), "{}", ret);
+        // alice:wrong, bob:builder, carol:singer
+        for credentials in &["YWxpY2U6d3Jvbmc=", "Ym9iOmJ1aWxkZXI=",
+                             "Y2Fyb2w6c2luZ2Vy", "not base64"] {
+            let ret = get(Some(credentials));
+            assert!(ret.starts_with("HTTP/1.1 401 "), "{}", ret);
+        }
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn digest_auth() {
+        let (path, users) = password_file("digest");
+        let port = port();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+        let _s = Server::start(cfg(port), auth::Digest::new("test", users, WhoAmI))
+            .unwrap();
+
+        let ret = request(addr, "GET /a?b HTTP/1.0\r\n\r\n");
+        assert!(ret.starts_with("HTTP/1.1 401 "), "{}", ret);
+        let start = ret.find("nonce=\"").unwrap() + 7;
+        let nonce = &ret[start..start + ret[start..].find('"').unwrap()];
+
+        // Each request made with the nonce counts up.
+        let nc = ::std::cell::Cell::new(0);
+        let authorization = |user: &str, password: &str, uri: &str, nonce: &str| {
+            nc.set(nc.get() + 1);
+            let ha1 = auth::ha1(user, "test", password);
+            let ha2 = ::raw::md5(&format!("GET:{}", uri)).unwrap();
+            let response = ::raw::md5(&format!("{}:{}:{:08x}:xyz:auth:{}",
+                                               ha1, nonce, nc.get(),
+                                               ha2)).unwrap();
+            format!("Authorization: Digest username=\"{}\", realm=\"test\", \
+                     nonce=\"{}\", uri=\"{}\", qop=auth, nc={:08x}, \
+                     cnonce=\"xyz\", response=\"{}\"\r\n",
+                    user, nonce, uri, nc.get(), response)
+        };
+        let target = |user: &str, password: &str, target: &str, uri: &str,
+                      nonce: &str| {
+            request(addr, &format!("GET {} HTTP/1.0\r\n{}\r\n", target,
+                                   authorization(user, password, uri, nonce)))
+        };
+        let get = |user: &str, password: &str, uri: &str, nonce: &str| {
+            target(user, password, "/a?b", uri, nonce)
+        };
+        let ret = get("alice", "wonderland", "/a?b", nonce);
+        assert!(ret.starts_with("HTTP/1.1 200 ") && ret.ends_with("\r\nalice"),
+                "{}", ret);
+        // civetweb decodes the target, but the client signs it as it was.
+        let ret = target("alice", "wonderland", "/a%20b?c", "/a%20b?c", nonce);
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        let ret = target("alice", "wonderland", "/a%20b?c", "/a%20b?d", nonce);
+        assert!(ret.starts_with("HTTP/1.1 401 "), "{}", ret);
+        for ret in &[get("alice", "wrong", "/a?b", nonce),
+                     get("alice", "wonderland", "/c", nonce),
+                     get("alice", "wonderland", "/a?b", "0.forged"),
+                     get("carol", "singer", "/a?b", nonce)] {
+            assert!(ret.starts_with("HTTP/1.1 401 "), "{}", ret);
+            assert!(!ret.contains("stale"), "{}", ret);
+        }
+
+        // A request sent again, or with a count that doesn't go up, is
+        // answered with a new nonce.
+        let replayed = format!("GET /a?b HTTP/1.0\r\n{}\r\n",
+                               authorization("alice", "wonderland", "/a?b", nonce));
+        let ret = request(addr, &replayed);
+        assert!(ret.starts_with("HTTP/1.1 200 "), "{}", ret);
+        let ret = request(addr, &replayed);
+        assert!(ret.starts_with("HTTP/1.1 401 ") && ret.contains("stale=true"),
+                "{}", ret);
+        nc.set(1);
+        let ret = get("alice", "wonderland", "/a?b", nonce);
+        assert!(ret.starts_with("HTTP/1.1 401 ") && ret.contains("stale=true"),
+                "{}", ret);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    fn fixture(name: &str) -> ::std::path::PathBuf {
+        ::std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls")
+            .join(name)
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_connect(addr: SocketAddr, name: &str, cert: Option<&str>)
+                   -> io::Result<::openssl::ssl::SslStream<TcpStream>> {
+        use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_ca_file(fixture("ca.pem")).unwrap();
+        if let Some(cert) = cert {
+            connector.set_certificate_chain_file(fixture(cert)).unwrap();
+            connector.set_private_key_file(fixture(cert), SslFiletype::PEM)
+                .unwrap();
+        }
+        let s = try!(TcpStream::connect(&addr));
+        connector.build().connect(name, s).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, e.to_string())
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_request(addr: SocketAddr, cert: Option<&str>,
+                   req: &str) -> io::Result<String> {
+        let mut s = try!(tls_connect(addr, "localhost", cert));
+        try!(s.write_all(req.as_bytes()));
+        let mut ret = String::new();
+        try!(s.read_to_string(&mut ret));
+        Ok(ret)
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn client_certificates() {
+        struct Foo(Mutex<Sender<Option<(String, String, bool)>>>);
+        impl Handler for Foo {
+            fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+                let msg = req.client_certificate().map(|cert| {
+                    (cert.subject, cert.issuer,
+                     cert.pem.map_or(false, |pem| pem.starts_with("-----BEGIN")))
+                });
+                self.0.lock().unwrap().send(msg).unwrap();
+                Ok(response(200, HashMap::new(), Cursor::new(vec![])))
+            }
+        }
+
+        let (tx, rx) = channel();
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let mut cfg = Config::new();
+        cfg.threads(1).listen_tls(addr)
+           .ssl_certificate(&fixture("server.pem"))
+           .ssl_verify_peer(true)
+           .ssl_ca_file(&fixture("ca.pem"));
+        let _s = Server::start(cfg, Foo(Mutex::new(tx))).unwrap();
+
+        let req = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let ret = tls_request(addr, Some("client.pem"), req).unwrap();
+        assert!(ret.starts_with("HTTP/1.1 200"), "{}", ret);
+        assert_eq!(rx.recv().unwrap(),
+                   Some(("/CN=civet test client".to_string(),
+                         "/CN=civet test CA".to_string(), true)));
+
+        let ret = tls_request(addr, None, req);
+        assert!(ret.map(|r| !r.starts_with("HTTP/1.1 200")).unwrap_or(true));
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// The common name on the certificate served to clients asking for
+    /// `name`.
+    #[cfg(feature = "tls")]
+    fn served_certificate(addr: SocketAddr, name: &str,
+                          cert: Option<&str>) -> String {
+        use openssl::nid::Nid;
+
+        let s = tls_connect(addr, name, cert).unwrap();
+        let cert = s.ssl().peer_certificate().unwrap();
+        let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()
+            .unwrap();
+        String::from_utf8(cn.data().as_slice().to_vec()).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn reloads_certificates() {
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let mut config = Config::new();
+        config.threads(1).listen_tls(addr)
+              .ssl_certificate(&fixture("server.pem"));
+        let s = Server::start(config, noop).unwrap();
+        assert_eq!(served_certificate(addr, "localhost", None), "localhost");
+
+        let renewed = fixture("renewed.pem");
+        s.reload_tls(&renewed, &renewed).unwrap();
+        assert_eq!(served_certificate(addr, "localhost", None),
+                   "civet test renewed");
+        assert!(s.reload_tls(&fixture("missing.pem"), &renewed).is_err());
+        assert_eq!(served_certificate(addr, "localhost", None),
+                   "civet test renewed");
+
+        let plain = Server::start(cfg(port()), noop).unwrap();
+        assert!(plain.reload_tls(&renewed, &renewed).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn reloads_certificates_keeping_client_verification() {
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let mut config = Config::new();
+        config.threads(1).listen_tls(addr)
+              .ssl_certificate(&fixture("server.pem"))
+              .ssl_verify_peer(true)
+              .ssl_ca_file(&fixture("ca.pem"));
+        let s = Server::start(config, Site("ok")).unwrap();
+        let renewed = fixture("renewed.pem");
+        s.reload_tls(&renewed, &renewed).unwrap();
+        assert_eq!(served_certificate(addr, "localhost", Some("client.pem")),
+                   "civet test renewed");
+
+        let req = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let ret = tls_request(addr, Some("client.pem"), req).unwrap();
+        assert!(ret.starts_with("HTTP/1.1 200"), "{}", ret);
+        let ret = tls_request(addr, None, req);
+        assert!(ret.map(|r| !r.starts_with("HTTP/1.1 200")).unwrap_or(true));
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_hosts() {
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let example = fixture("example.pem");
+        let mut config = Config::new();
+        config.threads(1).listen_tls(addr)
+              .ssl_certificate(&fixture("server.pem"))
+              .tls_host("Example.org", &example, &example);
+        let mut hosts = VirtualHosts::new(Site("default"));
+        hosts.host("example.org", Site("example"));
+        let _s = Server::start(config, hosts).unwrap();
+
+        assert_eq!(served_certificate(addr, "localhost", None), "localhost");
+        assert_eq!(served_certificate(addr, "example.org", None), "example.org");
+
+        // Without a `Host` header, the SNI name picks the site.
+        let mut s = tls_connect(addr, "example.org", None).unwrap();
+        s.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut ret = String::new();
+        s.read_to_string(&mut ret).unwrap();
+        assert!(ret.ends_with("\r\n\r\nexample"), "{}", ret);
+
+        let mut config = Config::new();
+        config.port(port()).tls_host("example.org", &fixture("missing.pem"),
+                                     &example);
+        assert!(Server::start(config, noop).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_hosts_verify_client_certificates() {
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port()));
+        let example = fixture("example.pem");
+        let mut config = Config::new();
+        config.threads(1).listen_tls(addr)
+              .ssl_certificate(&fixture("server.pem"))
+              .tls_host("example.org", &example, &example)
+              .ssl_verify_peer(true)
+              .ssl_ca_file(&fixture("ca.pem"));
+        let _s = Server::start(config, Site("ok")).unwrap();
+
+        for &(cert, ok) in &[(Some("client.pem"), true), (None, false)] {
+            let ret = tls_connect(addr, "example.org", cert).and_then(|mut s| {
+                try!(s.write_all(b"GET / HTTP/1.0\r\n\r\n"));
+                let mut ret = String::new();
+                try!(s.read_to_string(&mut ret));
+                Ok(ret)
+            });
+            let served = ret.map(|r| r.ends_with("\r\n\r\nok")).unwrap_or(false);
+            assert_eq!(served, ok, "client certificate {:?}", cert);
+        }
+    }
 }
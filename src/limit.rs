@@ -0,0 +1,196 @@
+//! Limiting how often clients can make requests.
+//!
+//! `RateLimit` wraps a handler and gives each client a token bucket: a
+//! request takes a token, tokens come back at a steady rate, and a client
+//! with none left is answered with a 429 and a `Retry-After` header instead
+//! of reaching the handler. `Config::throttle` limits bandwidth instead.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use conduit::{Handler, Request, Response};
+
+/// The most clients given a bucket of their own. Once there are this many,
+/// those whose buckets have filled up again are forgotten, and if that
+/// doesn't make room, new clients share one bucket until it does.
+const MAX_BUCKETS: usize = 4096;
+
+/// Clients that arrive while every bucket is taken by a busy client share
+/// one overflow bucket. It has the same rate and burst as the others, so
+/// those clients are limited as if they were one, and a single busy one
+/// among them can leave the rest with 429s until buckets free up.
+pub struct RateLimit<H> {
+    rate: f64,
+    burst: f64,
+    key: Box<Fn(&Request) -> Option<String> + Send + Sync>,
+    buckets: Mutex<Buckets>,
+    handler: H,
+}
+
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    /// Shared by the clients that didn't get a bucket of their own.
+    overflow: Bucket,
+    /// When full buckets were last looked for, so that a map full of busy
+    /// clients isn't searched again for every new one.
+    pruned: Option<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<H: Handler> RateLimit<H> {
+    /// Lets each client make `requests` requests every `per`, keyed by
+    /// `remote_addr` (so clients behind a trusted proxy are told apart).
+    /// Up to `requests` can be made at once.
+    ///
+    /// Fails with `InvalidInput` if `requests` or `per` is zero: no tokens
+    /// would ever come back, or they would come back all at once.
+    pub fn new(requests: u32, per: Duration, handler: H)
+               -> io::Result<RateLimit<H>> {
+        if requests == 0 || per == Duration::new(0, 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "a rate limit needs a number of \
+                                       requests and a period above zero"))
+        }
+        let per = per.as_secs() as f64 + per.subsec_nanos() as f64 / 1e9;
+        Ok(RateLimit {
+            rate: requests as f64 / per,
+            burst: requests as f64,
+            key: Box::new(|req: &Request| Some(req.remote_addr().ip().to_string())),
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                overflow: Bucket { tokens: requests as f64, updated: Instant::now() },
+                pruned: None,
+            }),
+            handler: handler,
+        })
+    }
+
+    /// How many requests a client, or the clients sharing the overflow
+    /// bucket, can make at once after being idle. At least one.
+    pub fn burst(&mut self, requests: u32) -> &mut RateLimit<H> {
+        self.burst = cmp::max(requests, 1) as f64;
+        self.buckets.lock().unwrap().overflow.tokens = self.burst;
+        self
+    }
+
+    /// Counts requests against whatever `key` returns for them, like an API
+    /// token, instead of the client's address. Requests it returns `None`
+    /// for aren't limited.
+    pub fn key<F>(&mut self, key: F) -> &mut RateLimit<H>
+        where F: Fn(&Request) -> Option<String> + Send + Sync + 'static
+    {
+        self.key = Box::new(key);
+        self
+    }
+
+    /// Takes a token for `key`, or says how long until one is available.
+    fn take(&self, key: String) -> Result<(), Duration> {
+        let now = Instant::now();
+        let (rate, burst) = (self.rate, self.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        if buckets.clients.len() >= MAX_BUCKETS && !buckets.clients.contains_key(&key) {
+            // No bucket can go from empty to full in less time than this, so
+            // there's no point looking again any sooner.
+            let refill = Duration::from_millis((burst / rate * 1000.0).max(1000.0) as u64);
+            if buckets.pruned.map_or(true, |pruned| now.duration_since(pruned) >= refill) {
+                buckets.clients.retain(|_, bucket| bucket.refill(now, rate, burst) < burst);
+                buckets.pruned = Some(now);
+            }
+        }
+        let bucket = if buckets.clients.len() < MAX_BUCKETS ||
+                        buckets.clients.contains_key(&key) {
+            buckets.clients.entry(key).or_insert_with(|| {
+                Bucket { tokens: burst, updated: now }
+            })
+        } else {
+            &mut buckets.overflow
+        };
+        if bucket.refill(now, self.rate, self.burst) >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.rate;
+            Err(Duration::new(wait.ceil() as u64, 0))
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+impl<H: Handler> Handler for RateLimit<H> {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let limited = match (self.key)(req) {
+            Some(key) => self.take(key),
+            None => Ok(()),
+        };
+        match limited {
+            Ok(()) => self.handler.call(req),
+            Err(wait) => {
+                let mut headers = HashMap::new();
+                headers.insert("Retry-After".to_string(),
+                               vec![wait.as_secs().to_string()]);
+                headers.insert("Content-Length".to_string(),
+                               vec!["0".to_string()]);
+                Ok(::response((429, "Too Many Requests"), headers, io::empty()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use conduit::{Request, Response};
+
+    use super::{RateLimit, MAX_BUCKETS};
+
+    fn noop(_: &mut Request) -> io::Result<Response> {
+        unreachable!()
+    }
+
+    #[test]
+    fn caps_buckets() {
+        let mut limit = RateLimit::new(1, Duration::from_secs(3600), noop)
+            .unwrap();
+        limit.burst(2);
+        for i in 0..MAX_BUCKETS {
+            assert!(limit.take(i.to_string()).is_ok());
+        }
+        // Every client is busy, so the rest share a bucket, with the same
+        // burst as the others.
+        assert!(limit.take("a".to_string()).is_ok());
+        assert!(limit.take("b".to_string()).is_ok());
+        assert!(limit.take("c".to_string()).is_err());
+        assert!(limit.take("0".to_string()).is_ok());
+        assert!(limit.take("0".to_string()).is_err());
+        assert_eq!(limit.buckets.lock().unwrap().clients.len(), MAX_BUCKETS);
+    }
+
+    #[test]
+    fn rejects_zero_rates() {
+        for &(requests, secs) in &[(0, 60), (5, 0)] {
+            let err = RateLimit::new(requests, Duration::from_secs(secs), noop)
+                .err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
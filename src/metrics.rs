@@ -0,0 +1,110 @@
+//! Exposing `Server::stats` to Prometheus.
+//!
+//! `Metrics` is a handler that answers with the statistics of the server it
+//! runs in, in the Prometheus text exposition format; route `/metrics` to it
+//! from the application's router, behind `Config::access_control` or a mount
+//! with its own if it shouldn't be public.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::io::{self, Cursor};
+use std::time::Duration;
+
+use conduit::{Handler, Request, Response};
+
+use stats::ServerStats;
+
+/// Serves the statistics of the server it runs in.
+pub struct Metrics;
+
+impl Handler for Metrics {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let stats = match req.extensions().find::<::Stats>() {
+            Some(&::Stats(ref recorder)) => recorder.snapshot(),
+            None => {
+                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "not a civet request")))
+            }
+        };
+        let body = render(&stats).into_bytes();
+        let mut headers = HashMap::new();
+        headers.insert("Content-Type".to_string(),
+                       vec!["text/plain; version=0.0.4; charset=utf-8".to_string()]);
+        headers.insert("Content-Length".to_string(),
+                       vec![body.len().to_string()]);
+        headers.insert("Cache-Control".to_string(),
+                       vec!["no-store".to_string()]);
+        Ok(::response(200, headers, Cursor::new(body)))
+    }
+}
+
+/// Formats `stats` in the Prometheus text exposition format, for serving
+/// them some other way than with `Metrics`.
+pub fn render(stats: &ServerStats) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "civet_requests_total", "counter",
+           "Requests answered, by method and status.");
+    for (&(ref method, status), count) in &stats.responses {
+        writeln!(out, "civet_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                 escape(method), status, count).unwrap();
+    }
+
+    header(&mut out, "civet_request_duration_seconds", "histogram",
+           "How long requests took to answer.");
+    for &(bound, count) in &stats.latency.buckets {
+        writeln!(out, "civet_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                 seconds(bound), count).unwrap();
+    }
+    writeln!(out, "civet_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+             stats.latency.count).unwrap();
+    writeln!(out, "civet_request_duration_seconds_sum {}",
+             seconds(stats.latency.sum)).unwrap();
+    writeln!(out, "civet_request_duration_seconds_count {}",
+             stats.latency.count).unwrap();
+
+    let values = [
+        ("civet_received_bytes_total", "counter",
+         "Bytes of request bodies read.", stats.bytes_read as f64),
+        ("civet_sent_bytes_total", "counter",
+         "Bytes of responses written, headers included.",
+         stats.bytes_written as f64),
+        ("civet_denied_requests_total", "counter",
+         "Requests refused by the access control lists.",
+         stats.denied_requests as f64),
+        ("civet_shed_requests_total", "counter",
+         "Connections refused with a 503 to shed load.",
+         stats.shed_requests as f64),
+        ("civet_active_requests", "gauge",
+         "Requests being handled, each holding a worker and its connection.",
+         stats.active_requests as f64),
+        ("civet_worker_threads", "gauge",
+         "Worker threads, each serving one connection at a time.",
+         stats.threads as f64),
+        ("civet_worker_utilization", "gauge",
+         "The fraction of worker threads handling a request.",
+         stats.active_requests as f64 / stats.threads.max(1) as f64),
+        ("civet_uptime_seconds", "gauge",
+         "How long the server has been running.", seconds(stats.uptime)),
+    ];
+    for &(name, kind, help, value) in values.iter() {
+        header(&mut out, name, kind, help);
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,291 @@
+//! Working out who the client really is when a request comes through a proxy
+//! we trust, from RFC 7239 `Forwarded` or the `X-Forwarded-*` headers.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use conduit::Scheme;
+
+/// A block of addresses such as `10.0.0.0/8` or `fd00::/8`. A bare address
+/// is a block of one.
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Cidr, ()> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = try!(parts.next().unwrap().parse::<IpAddr>().map_err(|_| ()));
+        let max = match addr { IpAddr::V4(..) => 32, IpAddr::V6(..) => 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => try!(prefix.parse::<u8>().map_err(|_| ())),
+            None => max,
+        };
+        if prefix > max {
+            return Err(())
+        }
+        Ok(Cidr { addr: canonical(addr), prefix: prefix })
+    }
+}
+
+impl Cidr {
+    pub fn is_ipv4(&self) -> bool {
+        match self.addr { IpAddr::V4(..) => true, IpAddr::V6(..) => false }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                prefix_matches(&a.octets(), &b.octets(), self.prefix)
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                prefix_matches(&a.octets(), &b.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv4 peers can show up as IPv4-mapped IPv6 addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                IpAddr::V4(v6.to_ipv4().unwrap())
+            } else {
+                ip
+            }
+        }
+        ip => ip,
+    }
+}
+
+fn prefix_matches(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let bytes = (bits / 8) as usize;
+    let rest = bits % 8;
+    a[..bytes] == b[..bytes] &&
+        (rest == 0 || (a[bytes] ^ b[bytes]) >> (8 - rest) == 0)
+}
+
+/// What the proxies told us about the client.
+pub struct Forwarded {
+    pub addr: SocketAddr,
+    pub scheme: Option<Scheme>,
+    pub host: Option<String>,
+}
+
+struct Hop {
+    addr: Option<SocketAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Resolves the client behind `peer` from the request `headers`, if `peer`
+/// is a trusted proxy that forwarded anything.
+///
+/// The addresses in the chain are walked from the nearest proxy outwards
+/// for as long as they are trusted; the first untrusted one is the client.
+/// Anything before it may have come from the client and is ignored.
+pub fn resolve(proxies: &[Cidr], peer: SocketAddr, headers: &[(&str, &str)])
+               -> Option<Forwarded> {
+    let trusted = |ip: IpAddr| proxies.iter().any(|cidr| cidr.contains(ip));
+    if !trusted(peer.ip()) {
+        return None
+    }
+
+    let values = |name: &str| {
+        headers.iter()
+            .filter(|&&(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|&(_, v)| split_quoted(v, ','))
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values("Forwarded");
+    let (hops, proto, host) = if !forwarded.is_empty() {
+        (forwarded.iter().map(|element| parse_element(element)).collect(),
+         None, None)
+    } else {
+        let hops = values("X-Forwarded-For").iter().map(|node| {
+            Hop { addr: parse_node(node), proto: None, host: None }
+        }).collect::<Vec<_>>();
+        // Only the nearest proxy's word can be trusted for these.
+        let proto = values("X-Forwarded-Proto").last().map(|s| s.to_string());
+        let host = values("X-Forwarded-Host").last().map(|s| s.to_string());
+        if hops.is_empty() && proto.is_none() && host.is_none() {
+            return None
+        }
+        (hops, proto, host)
+    };
+
+    let mut client = Forwarded { addr: peer, scheme: None, host: None };
+    let mut chosen: Option<&Hop> = None;
+    for hop in hops.iter().rev() {
+        chosen = Some(hop);
+        match hop.addr {
+            Some(addr) => {
+                client.addr = addr;
+                if !trusted(addr.ip()) {
+                    break
+                }
+            }
+            None => break,
+        }
+    }
+    let proto = proto.or(chosen.and_then(|hop| hop.proto.clone()));
+    client.scheme = proto.and_then(|proto| {
+        if proto.eq_ignore_ascii_case("https") {
+            Some(Scheme::Https)
+        } else if proto.eq_ignore_ascii_case("http") {
+            Some(Scheme::Http)
+        } else {
+            None
+        }
+    });
+    client.host = host.or(chosen.and_then(|hop| hop.host.clone()));
+    Some(client)
+}
+
+/// Parses one element of a `Forwarded` header, like
+/// `for=192.0.2.60;proto=https;by=203.0.113.43`.
+fn parse_element(element: &str) -> Hop {
+    let mut hop = Hop { addr: None, proto: None, host: None };
+    for pair in split_quoted(element, ';') {
+        let mut parts = pair.splitn(2, '=');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), unquote(value.trim())),
+            _ => continue,
+        };
+        if name.eq_ignore_ascii_case("for") {
+            hop.addr = parse_node(&value);
+        } else if name.eq_ignore_ascii_case("proto") {
+            hop.proto = Some(value);
+        } else if name.eq_ignore_ascii_case("host") {
+            hop.host = Some(value);
+        }
+    }
+    hop
+}
+
+/// Parses a node such as `192.0.2.43`, `192.0.2.43:80` or `[2001:db8::1]:80`.
+/// Obfuscated and `unknown` nodes have no address.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, 0))
+    }
+    match ::split_host(node) {
+        Some((ip, port)) => {
+            let ip = if node.starts_with('[') {
+                ip.parse::<Ipv6Addr>().map(IpAddr::V6).ok()
+            } else {
+                ip.parse::<IpAddr>().ok()
+            };
+            ip.map(|ip| SocketAddr::new(ip, port.unwrap_or(0)))
+        }
+        None => None,
+    }
+}
+
+/// Splits `s` at `sep`, except inside quoted strings.
+pub fn split_quoted(s: &str, sep: char) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            ret.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    ret.push(&s[start..]);
+    ret
+}
+
+pub fn unquote(s: &str) -> String {
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return s.to_string()
+    }
+    let mut ret = String::new();
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => ret.extend(chars.next()),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use conduit::Scheme;
+    use super::{Cidr, resolve};
+
+    fn proxies() -> Vec<Cidr> {
+        vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs() {
+        let cidr = "192.168.0.0/23".parse::<Cidr>().unwrap();
+        assert!(cidr.contains("192.168.1.7".parse().unwrap()));
+        assert!(cidr.contains("::ffff:192.168.0.1".parse().unwrap()));
+        assert!(!cidr.contains("192.168.2.1".parse().unwrap()));
+        assert!("::1".parse::<Cidr>().unwrap().contains("::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn untrusted_peers_are_ignored() {
+        let headers = [("X-Forwarded-For", "1.2.3.4")];
+        assert!(resolve(&proxies(), addr("8.8.8.8:1000"), &headers).is_none());
+        assert!(resolve(&proxies(), addr("10.0.0.1:1000"), &[]).is_none());
+    }
+
+    #[test]
+    fn x_forwarded() {
+        let headers = [("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 10.0.0.2"),
+                       ("X-Forwarded-Proto", "https"),
+                       ("X-Forwarded-Host", "example.com")];
+        let client = resolve(&proxies(), addr("10.0.0.1:1000"), &headers)
+            .unwrap();
+        assert_eq!(client.addr, addr("1.2.3.4:0"));
+        assert_eq!(client.scheme, Some(Scheme::Https));
+        assert_eq!(client.host, Some("example.com".to_string()));
+    }
+
+    #[test]
+    fn forwarded() {
+        let headers = [("Forwarded", "for=6.6.6.6;proto=http"),
+                       ("Forwarded", "for=\"[2001:db8::1]:4711\";proto=https;\
+                                      host=\"example.com:8443\", \
+                                      for=\"[fd00::2]\";proto=http")];
+        let client = resolve(&proxies(), addr("10.0.0.1:1000"), &headers)
+            .unwrap();
+        assert_eq!(client.addr, addr("[2001:db8::1]:4711"));
+        assert_eq!(client.scheme, Some(Scheme::Https));
+        assert_eq!(client.host, Some("example.com:8443".to_string()));
+
+        let headers = [("Forwarded", "for=unknown;proto=https")];
+        let client = resolve(&proxies(), addr("10.0.0.1:1000"), &headers)
+            .unwrap();
+        assert_eq!(client.addr, addr("10.0.0.1:1000"));
+        assert_eq!(client.scheme, Some(Scheme::Https));
+    }
+}
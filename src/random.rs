@@ -0,0 +1,18 @@
+//! Randomness that has to be unpredictable, like names and secrets an
+//! attacker mustn't be able to guess, read from the operating system.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) -> io::Result<()> {
+    let mut urandom = try!(File::open("/dev/urandom"));
+    urandom.read_exact(buf)
+}
+
+/// `len` random bytes, as lowercase hex.
+pub fn hex(len: usize) -> io::Result<String> {
+    let mut buf = vec![0; len];
+    try!(fill(&mut buf));
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
@@ -0,0 +1,249 @@
+//! Byte ranges (RFC 7233).
+//!
+//! civetweb only answers `Range` requests for the files it serves itself.
+//! Handlers get the same for anything they can seek in, like a generated
+//! artifact on disk or a buffer, by building the response with `serve`: it
+//! sends the parts the client asked for, one as a `206` or several as
+//! `multipart/byteranges`, so interrupted downloads can be resumed.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use conduit::{Method, Request, Response, WriteBody};
+
+use date;
+
+/// More ranges than this in one request are more likely an attempt to make
+/// the server do a lot of work than a real client, so the whole body is
+/// sent instead.
+const MAX_RANGES: usize = 32;
+
+/// Responds to `req` with `body`, or the ranges of it `req` asks for. The
+/// length of `body` is found by seeking to its end.
+///
+/// `headers` are those of the full response, and should include its
+/// `Content-Type`, and its `ETag` or `Last-Modified` if it has one so that
+/// `If-Range` works. Ranges are only served for `GET` requests.
+pub fn serve<R>(req: &Request, mut headers: HashMap<String, Vec<String>>,
+                mut body: R) -> io::Result<Response>
+    where R: Read + Seek + Send + 'static
+{
+    let len = try!(body.seek(SeekFrom::End(0)));
+    try!(body.seek(SeekFrom::Start(0)));
+    ::remove_response_header(&mut headers, "Content-Length");
+    headers.insert("Accept-Ranges".to_string(), vec!["bytes".to_string()]);
+
+    let ranges = match req.method() {
+        Method::Get if if_range(req, &headers) => {
+            req.headers().find("Range")
+                .and_then(|values| if values.len() == 1 { parse(values[0]) } else { None })
+        }
+        _ => None,
+    };
+    let ranges = match ranges {
+        Some(ranges) => satisfiable(&ranges, len),
+        None => {
+            headers.insert("Content-Length".to_string(), vec![len.to_string()]);
+            if req.method() == Method::Head {
+                return Ok(::response(200, headers, io::empty()))
+            }
+            return Ok(::response(200, headers, body))
+        }
+    };
+
+    match ranges.len() {
+        0 => {
+            let mut headers = HashMap::new();
+            headers.insert("Content-Range".to_string(), vec![format!("bytes */{}", len)]);
+            headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+            Ok(::response(416, headers, io::empty()))
+        }
+        1 => {
+            let (start, end) = ranges[0];
+            headers.insert("Content-Range".to_string(),
+                           vec![format!("bytes {}-{}/{}", start, end, len)]);
+            headers.insert("Content-Length".to_string(),
+                           vec![(end - start + 1).to_string()]);
+            try!(body.seek(SeekFrom::Start(start)));
+            Ok(::response(206, headers, body.take(end - start + 1)))
+        }
+        _ => {
+            let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+            let content_type = ::response_header(&headers, "Content-Type")
+                .map(|content_type| format!("Content-Type: {}\r\n", content_type))
+                .unwrap_or_default();
+            let parts = ranges.iter().map(|&(start, end)| {
+                let head = format!("\r\n--{}\r\n{}Content-Range: bytes {}-{}/{}\r\n\r\n",
+                                   boundary, content_type, start, end, len);
+                (head, start, end - start + 1)
+            }).collect::<Vec<_>>();
+            let end = format!("\r\n--{}--\r\n", boundary);
+            let length = parts.iter()
+                .map(|&(ref head, _, len)| head.len() as u64 + len)
+                .sum::<u64>() + end.len() as u64;
+
+            ::remove_response_header(&mut headers, "Content-Type");
+            headers.insert("Content-Type".to_string(),
+                           vec![format!("multipart/byteranges; boundary={}", boundary)]);
+            headers.insert("Content-Length".to_string(), vec![length.to_string()]);
+            Ok(Response {
+                status: (206, "Partial Content"),
+                headers: headers,
+                body: Box::new(Multipart { body: body, parts: parts, end: end }),
+            })
+        }
+    }
+}
+
+/// Whether the request's `If-Range`, if it has one, says the client's copy
+/// is of the same representation as the one in `headers`, so the ranges it
+/// asks for can be combined with it.
+fn if_range(req: &Request, headers: &HashMap<String, Vec<String>>) -> bool {
+    let condition = match req.headers().find("If-Range") {
+        Some(values) => values.join(","),
+        None => return true,
+    };
+    let condition = condition.trim();
+    if condition.starts_with('"') {
+        // Only a strong tag will do, so plain equality is enough.
+        ::response_header(headers, "ETag").map_or(false, |etag| etag.trim() == condition)
+    } else if condition.starts_with("W/") {
+        false
+    } else {
+        let modified = ::response_header(headers, "Last-Modified").and_then(date::parse);
+        modified.is_some() && modified == date::parse(condition)
+    }
+}
+
+/// Parses a `Range` header into `(first, last)` pairs, either of which can
+/// be left out, or gives `None` if it isn't one we understand.
+fn parse(header: &str) -> Option<Vec<(Option<u64>, Option<u64>)>> {
+    let header = header.trim();
+    match header.get(..6) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=") => {}
+        _ => return None,
+    }
+    let mut ranges = Vec::new();
+    for spec in header[6..].split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue
+        }
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return None,
+        };
+        let range = match (number(&spec[..dash]), number(&spec[dash + 1..])) {
+            (Ok(None), Ok(None)) | (Err(()), _) | (_, Err(())) => return None,
+            (Ok(Some(first)), Ok(Some(last))) if last < first => return None,
+            (Ok(first), Ok(last)) => (first, last),
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() || ranges.len() > MAX_RANGES {
+        return None
+    }
+    Some(ranges)
+}
+
+fn number(s: &str) -> Result<Option<u64>, ()> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None)
+    }
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(())
+    }
+    s.parse().map(Some).map_err(|_| ())
+}
+
+/// The `(first, last)` offsets of the ranges that overlap a body of `len`
+/// bytes, clamped to it. Ranges that overlap or touch are merged, in order,
+/// so that no byte is sent more than once however the ranges are written.
+fn satisfiable(ranges: &[(Option<u64>, Option<u64>)], len: u64) -> Vec<(u64, u64)> {
+    let mut ranges = ranges.iter().filter_map(|&range| {
+        match range {
+            (Some(first), _) if first >= len => None,
+            (Some(first), Some(last)) => Some((first, last.min(len - 1))),
+            (Some(first), None) => Some((first, len - 1)),
+            (None, Some(suffix)) if suffix > 0 && len > 0 => {
+                Some((len - suffix.min(len), len - 1))
+            }
+            _ => None,
+        }
+    }).collect::<Vec<_>>();
+    ranges.sort();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(&mut (_, ref mut end)) if first <= *end + 1 => {
+                *end = (*end).max(last);
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+/// A `multipart/byteranges` body.
+struct Multipart<R> {
+    body: R,
+    /// Each part's headers, and where its bytes start and how many there are.
+    parts: Vec<(String, u64, u64)>,
+    end: String,
+}
+
+impl<R: Read + Seek> WriteBody for Multipart<R> {
+    fn write_body(&mut self, out: &mut Write) -> io::Result<u64> {
+        let mut written = 0;
+        for &(ref head, start, len) in &self.parts {
+            try!(out.write_all(head.as_bytes()));
+            try!(self.body.seek(SeekFrom::Start(start)));
+            written += try!(io::copy(&mut (&mut self.body).take(len), out));
+        }
+        try!(out.write_all(self.end.as_bytes()));
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, satisfiable};
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse("bytes=0-499"), Some(vec![(Some(0), Some(499))]));
+        assert_eq!(parse("bytes=500-, -200"), Some(vec![(Some(500), None), (None, Some(200))]));
+        assert_eq!(parse("Bytes= 1-2 ,, 3-4"), Some(vec![(Some(1), Some(2)), (Some(3), Some(4))]));
+        assert_eq!(parse("bytes=5-4"), None);
+        assert_eq!(parse("bytes=-"), None);
+        assert_eq!(parse("bytes=1-2, x-3"), None);
+        assert_eq!(parse("bytes=+1-2"), None);
+        assert_eq!(parse("bytes="), None);
+        assert_eq!(parse("items=0-1"), None);
+        assert_eq!(parse(&format!("bytes={}", vec!["0-0"; 33].join(","))), None);
+        assert_eq!(parse("bytesé=0-1"), None);
+        assert_eq!(parse("byté"), None);
+    }
+
+    #[test]
+    fn clamps_ranges() {
+        let ranges = [(Some(0), Some(2)), (Some(8), Some(100)), (Some(10), None),
+                      (None, Some(0))];
+        assert_eq!(satisfiable(&ranges, 10), vec![(0, 2), (8, 9)]);
+        assert_eq!(satisfiable(&[(None, Some(3))], 10), vec![(7, 9)]);
+        assert_eq!(satisfiable(&[(None, Some(100))], 10), vec![(0, 9)]);
+        assert_eq!(satisfiable(&ranges, 0), vec![]);
+    }
+
+    #[test]
+    fn merges_ranges() {
+        assert_eq!(satisfiable(&[(Some(0), None); 32], 10), vec![(0, 9)]);
+        let ranges = [(Some(6), Some(7)), (Some(0), Some(1)), (Some(2), Some(3)),
+                      (Some(7), Some(8)), (None, Some(1))];
+        assert_eq!(satisfiable(&ranges, 20), vec![(0, 3), (6, 8), (19, 19)]);
+    }
+}
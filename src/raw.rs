@@ -1,14 +1,15 @@
-use libc::{c_void,c_char,c_int,c_longlong,size_t};
+use libc::{self,c_void,c_char,c_int,c_longlong,size_t};
+use std::cmp;
 use std::ffi::{CStr, CString};
 use std::io;
 use std::marker;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::mem::transmute;
-use std::ptr::{null, null_mut};
+use std::ptr::null;
 use std::panic;
 use std::str;
 
-use Config;
-
 extern {
     fn mg_start(callbacks: *const MgCallbacks, user_data: *mut c_void,
                 options: *const *mut c_char) -> *mut MgContext;
@@ -22,6 +23,27 @@ extern {
     fn mg_get_header(connection: *mut MgConnection,
                      name: *const c_char) -> *const c_char;
     fn mg_get_request_info(connection: *mut MgConnection) -> *mut MgRequestInfo;
+    fn mg_md5(buf: *mut c_char, ...) -> *mut c_char;
+    fn mg_modify_passwords_file(passwords_file_name: *const c_char,
+                                domain: *const c_char, user: *const c_char,
+                                password: *const c_char) -> c_int;
+
+    // Accessors civet-sys adds for fields of civetweb's private
+    // `struct mg_connection` and `struct mg_context`.
+    #[cfg(feature = "tls")]
+    fn civet_get_ssl(connection: *mut MgConnection) -> *mut c_void;
+    fn civet_get_socket(connection: *mut MgConnection) -> c_int;
+    fn civet_set_must_close(connection: *mut MgConnection);
+    fn civet_request_target(connection: *mut MgConnection) -> *const c_char;
+    fn civet_read_trailers(connection: *mut MgConnection, buf: *mut c_char,
+                           len: size_t) -> c_int;
+    fn civet_set_listen_backlog(context: *mut MgContext, backlog: c_int) -> c_int;
+    fn civet_handle_form_request(connection: *mut MgConnection,
+                                 handler: *mut MgFormDataHandler) -> c_int;
+    fn civet_set_connection_queue(context: *mut MgContext, limit: c_int,
+                                  reply: *const c_char,
+                                  rejected: Option<extern fn(*mut c_void)>)
+                                  -> c_int;
 }
 
 pub enum MgContext {}
@@ -30,13 +52,38 @@ pub struct Server<T: Sync + 'static>(*mut MgContext, Box<ServerCallback<T>>);
 
 pub struct ServerCallback<T> {
     callback: fn(&mut Connection, &T) -> Result<(), ()>,
+    init_ssl: Option<fn(*mut c_void, &T) -> Result<(), ()>>,
+    queue_full: Option<fn(&T)>,
+    queue_reply: Option<CString>,
     param: T,
 }
 
 impl<T: Sync> ServerCallback<T> {
     pub fn new(callback: fn(&mut Connection, &T) -> Result<(), ()>,
                param: T) -> ServerCallback<T> {
-        ServerCallback { callback: callback, param: param }
+        ServerCallback {
+            callback: callback,
+            init_ssl: None,
+            queue_full: None,
+            queue_reply: None,
+            param: param,
+        }
+    }
+
+    /// Turns away connections that arrive while the queue set with
+    /// `Server::set_connection_queue` is full, calling `queue_full` for each.
+    pub fn queue_full(mut self, queue_full: fn(&T)) -> ServerCallback<T> {
+        self.queue_full = Some(queue_full);
+        self
+    }
+
+    /// Hands civetweb's `SSL_CTX *` to `init_ssl` once it has been created,
+    /// before civetweb loads its own certificate into it.
+    #[cfg(feature = "tls")]
+    pub fn init_ssl(mut self, init_ssl: fn(*mut c_void, &T) -> Result<(), ()>)
+                    -> ServerCallback<T> {
+        self.init_ssl = Some(init_ssl);
+        self
     }
 }
 
@@ -45,18 +92,23 @@ impl<T: 'static + Sync> Server<T> {
         let Server(context, _) = *self; context
     }
 
-    pub fn start(options: Config,
+    pub fn start(options: &[*const c_char],
                  callback: ServerCallback<T>) -> io::Result<Server<T>> {
-        let (_a, ptrs) = ::config::config_to_options(&options);
-
-        let context = start(ptrs.as_ptr() as *const _);
+        let mut callback = Box::new(callback);
+        let mut callbacks = MgCallbacks::new();
+        if callback.init_ssl.is_some() {
+            callbacks.init_ssl = raw_init_ssl::<T> as *const c_void;
+        }
+        let context = unsafe {
+            mg_start(&callbacks, &mut *callback as *mut _ as *mut c_void,
+                     options.as_ptr() as *const _)
+        };
         // TODO: fill in this error
         if context.is_null() {
             return Err(io::Error::new(io::ErrorKind::Other, "other error"))
         }
 
         let uri = CString::new("**").unwrap();
-        let mut callback = Box::new(callback);
         unsafe {
             mg_set_request_handler(context, uri.as_ptr(),
                                    raw_handler::<T>,
@@ -64,6 +116,46 @@ impl<T: 'static + Sync> Server<T> {
         }
         Ok(Server(context, callback))
     }
+
+    /// Sets the backlog of the server's listening sockets, which civetweb
+    /// starts with its compile-time `SOMAXCONN`.
+    pub fn set_listen_backlog(&self, backlog: u32) -> io::Result<()> {
+        let backlog = cmp::min(backlog, c_int::max_value() as u32) as c_int;
+        match unsafe { civet_set_listen_backlog(self.as_ptr(), backlog) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Queues at most `limit` accepted connections for the worker threads,
+    /// rather than civetweb's 20. When the queue is full, connections that
+    /// arrive are sent `reply`, unless they use TLS, and closed if the
+    /// callback has a `queue_full` function; otherwise the server stops
+    /// accepting them until a worker takes one off the queue. civetweb reads
+    /// `reply` without a lock, so set the queue once, before serving.
+    pub fn set_connection_queue(&mut self, limit: u32, reply: Option<CString>)
+                                -> io::Result<()> {
+        let rejected = match self.1.queue_full {
+            Some(..) => Some(raw_queue_full::<T> as extern fn(*mut c_void)),
+            None => None,
+        };
+        self.1.queue_reply = reply;
+        let reply = self.1.queue_reply.as_ref().map_or(null(), |r| r.as_ptr());
+        let limit = cmp::min(limit, c_int::max_value() as u32) as c_int;
+        let ret = unsafe {
+            civet_set_connection_queue(self.as_ptr(), limit, reply, rejected)
+        };
+        if ret != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("connection queue of {} is out \
+                                               of range", limit)))
+        }
+        Ok(())
+    }
+
+    pub fn param(&self) -> &T {
+        &self.1.param
+    }
 }
 
 impl<T: 'static + Sync> Drop for Server<T> {
@@ -92,8 +184,45 @@ extern fn raw_handler<T: 'static>(conn: *mut MgConnection,
     }
 }
 
+extern fn raw_queue_full<T: 'static>(user_data: *mut c_void) {
+    struct Env(*mut c_void);
+    unsafe impl Send for Env {}
+
+    let env = Env(user_data);
+    let _ = panic::catch_unwind(move || {
+        let Env(user_data) = env;
+        let callback: &ServerCallback<T> = unsafe { transmute(user_data) };
+        if let Some(queue_full) = callback.queue_full {
+            queue_full(&callback.param)
+        }
+    });
+}
+
+extern fn raw_init_ssl<T: 'static>(ssl_ctx: *mut c_void,
+                                   user_data: *mut c_void) -> c_int {
+    struct Env(*mut c_void, *mut c_void);
+    unsafe impl Send for Env {}
+
+    let env = Env(ssl_ctx, user_data);
+    let ret = panic::catch_unwind(move || {
+        let Env(ssl_ctx, user_data) = env;
+        let callback: &ServerCallback<T> = unsafe { transmute(user_data) };
+        match callback.init_ssl {
+            Some(init_ssl) => init_ssl(ssl_ctx, &callback.param),
+            None => Ok(()),
+        }
+    });
+
+    // 0 lets civetweb go on to set up the context from its own options.
+    match ret {
+        Ok(Ok(())) => 0,
+        _ => -1,
+    }
+}
+
 pub enum MgConnection {}
 
+#[derive(Clone, Copy)]
 pub struct Connection(*mut MgConnection);
 
 impl Connection {
@@ -104,6 +233,28 @@ impl Connection {
 
 type MgRequestHandler = extern fn(*mut MgConnection, *mut c_void) -> i32;
 
+pub const FORM_FIELD_STORAGE_SKIP: c_int = 0x0;
+pub const FORM_FIELD_STORAGE_GET: c_int = 0x1;
+pub const FORM_FIELD_STORAGE_STORE: c_int = 0x2;
+pub const FORM_FIELD_STORAGE_ABORT: c_int = 0x10;
+
+#[repr(C)]
+pub struct MgFormDataHandler {
+    pub field_found: extern fn(key: *const c_char, filename: *const c_char,
+                               path: *mut c_char, pathlen: size_t,
+                               user_data: *mut c_void) -> c_int,
+    pub field_get: extern fn(key: *const c_char, value: *const c_char,
+                             valuelen: size_t, user_data: *mut c_void) -> c_int,
+    pub field_store: extern fn(path: *const c_char, file_size: c_longlong,
+                               user_data: *mut c_void) -> c_int,
+    pub user_data: *mut c_void,
+    /// Reads the next piece of the request body, returning what `mg_read`
+    /// would. This and the fields above make up civet.c's
+    /// `struct civet_form_data_handler`.
+    pub read: extern fn(user_data: *mut c_void, buf: *mut c_void,
+                        len: size_t) -> c_int,
+}
+
 #[repr(C)]
 struct MgHeader {
     name: *const c_char,
@@ -149,10 +300,36 @@ pub struct MgRequestInfo {
     num_headers: c_int,
     headers: [MgHeader; 64],
 
-    client_cert: *mut c_void,
+    client_cert: *mut MgClientCert,
     accepted_websocket_subprotocol: *const c_char
 }
 
+#[repr(C)]
+pub struct MgClientCert {
+    subject: *const c_char,
+    issuer: *const c_char,
+    serial: *const c_char,
+    finger: *const c_char,
+}
+
+impl MgClientCert {
+    pub fn subject(&self) -> Option<&str> {
+        to_slice(self, |cert| cert.subject)
+    }
+
+    pub fn issuer(&self) -> Option<&str> {
+        to_slice(self, |cert| cert.issuer)
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        to_slice(self, |cert| cert.serial)
+    }
+
+    pub fn fingerprint(&self) -> Option<&str> {
+        to_slice(self, |cert| cert.finger)
+    }
+}
+
 pub struct RequestInfo<'a> {
     ptr: *mut MgRequestInfo,
     _marker: marker::PhantomData<&'a str>,
@@ -175,6 +352,14 @@ impl<'a> RequestInfo<'a> {
         to_slice(self.as_ref(), |info| info.uri)
     }
 
+    pub fn local_uri(&self) -> Option<&str> {
+        to_slice(self.as_ref(), |info| info.local_uri)
+    }
+
+    pub fn remote_user(&self) -> Option<&str> {
+        to_slice(self.as_ref(), |info| info.remote_user)
+    }
+
     pub fn http_version(&self) -> Option<&str> {
         to_slice(self.as_ref(), |info| info.http_version)
     }
@@ -198,6 +383,11 @@ impl<'a> RequestInfo<'a> {
     pub fn is_ssl(&self) -> bool {
         self.as_ref().is_ssl != 0
     }
+
+    /// The certificate the client presented, if it was verified.
+    pub fn client_cert(&self) -> Option<&MgClientCert> {
+        unsafe { self.as_ref().client_cert.as_ref() }
+    }
 }
 
 #[repr(C)]
@@ -247,14 +437,29 @@ fn to_slice<'a, T, F>(obj: &'a T, mut callback: F) -> Option<&'a str>
     Some(str::from_utf8(unsafe { CStr::from_ptr(chars).to_bytes() }).unwrap())
 }
 
-pub fn start(options: *const *mut c_char) -> *mut MgContext {
-    unsafe { mg_start(&MgCallbacks::new(), null_mut(), options) }
-}
-
 pub fn read(conn: &Connection, buf: &mut [u8]) -> i32 {
     unsafe { mg_read(conn.unwrap(), buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) }
 }
 
+/// Reads the trailer section after a chunked body that `read` has returned
+/// 0 for, including the empty line that ends it. See `civet_read_trailers`.
+pub fn read_trailers(conn: &Connection, buf: &mut [u8]) -> i32 {
+    unsafe {
+        civet_read_trailers(conn.unwrap(), buf.as_mut_ptr() as *mut c_char,
+                            buf.len() as size_t)
+    }
+}
+
+/// The request target as the client sent it, before civetweb split off the
+/// query string, decoded it and removed dot segments.
+pub fn request_target(conn: &Connection) -> Option<&str> {
+    let target = unsafe { civet_request_target(conn.unwrap()) };
+    if target.is_null() {
+        return None
+    }
+    str::from_utf8(unsafe { CStr::from_ptr(target).to_bytes() }).ok()
+}
+
 pub fn write(conn: &Connection, bytes: &[u8]) -> i32 {
     let c_bytes = bytes.as_ptr() as *const c_void;
     unsafe { mg_write(conn.unwrap(), c_bytes, bytes.len() as size_t) }
@@ -279,10 +484,108 @@ pub fn get_request_info<'a>(conn: &'a Connection) -> Option<RequestInfo<'a>> {
     }
 }
 
-pub fn get_headers<'a>(conn: &'a Connection) -> Vec<Header<'a>> {
+/// The connection's OpenSSL `SSL *`, null unless it is a TLS connection.
+#[cfg(feature = "tls")]
+pub fn ssl(conn: &Connection) -> *mut c_void {
+    unsafe { civet_get_ssl(conn.unwrap()) }
+}
+
+/// The address of our end of the connection.
+#[cfg(unix)]
+pub fn local_addr(conn: &Connection) -> Option<SocketAddr> {
+    let sock = unsafe { civet_get_socket(conn.unwrap()) };
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(sock, &mut storage as *mut _ as *mut libc::sockaddr,
+                          &mut len)
+    };
+    if ret != 0 {
+        return None
+    }
+    match storage.ss_family as c_int {
+        libc::AF_INET => {
+            let addr = unsafe {
+                &*(&storage as *const _ as *const libc::sockaddr_in)
+            };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe {
+                &*(&storage as *const _ as *const libc::sockaddr_in6)
+            };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::new(IpAddr::V6(ip), u16::from_be(addr.sin6_port)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn local_addr(_conn: &Connection) -> Option<SocketAddr> {
+    None
+}
+
+/// Makes civetweb close the connection once the handler returns, rather
+/// than read another request from it.
+pub fn force_close(conn: &Connection) {
+    unsafe { civet_set_must_close(conn.unwrap()) }
+}
+
+pub fn handle_form_request(conn: &Connection,
+                          handler: &mut MgFormDataHandler) -> i32 {
+    unsafe { civet_handle_form_request(conn.unwrap(), handler) }
+}
+
+/// The MD5 digest of `data`, in lowercase hex. `None` if `data` contains a
+/// NUL byte.
+pub fn md5(data: &str) -> Option<String> {
+    let data = match CString::new(data) {
+        Ok(data) => data,
+        Err(..) => return None,
+    };
+    let mut buf = [0 as c_char; 33];
+    unsafe {
+        mg_md5(buf.as_mut_ptr(), data.as_ptr(), null::<c_char>());
+        Some(CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned())
+    }
+}
+
+/// Sets the password of `user` in `domain` in an `.htpasswd`-style file,
+/// creating the file if needed, or removes the user if `password` is `None`.
+pub fn modify_passwords_file(path: &str, domain: &str, user: &str,
+                             password: Option<&str>) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput,
+                                    "invalid password file entry");
+    let path = try!(CString::new(path).map_err(|_| invalid()));
+    let domain = try!(CString::new(domain).map_err(|_| invalid()));
+    let user = try!(CString::new(user).map_err(|_| invalid()));
+    let password = match password {
+        Some(password) => Some(try!(CString::new(password).map_err(|_| invalid()))),
+        None => None,
+    };
+    let ret = unsafe {
+        mg_modify_passwords_file(path.as_ptr(), domain.as_ptr(), user.as_ptr(),
+                                 password.as_ref().map_or(null(), |p| p.as_ptr()))
+    };
+    if ret == 1 {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "failed to update the password file"))
+    }
+}
+
+/// The headers of the current request. Only the first `num_headers` slots
+/// are read: civetweb doesn't clear the rest between requests, so they can
+/// still hold headers from an earlier request on the connection.
+pub fn request_headers<'a>(conn: &'a Connection) -> Vec<Header<'a>> {
     match get_request_info(conn) {
         Some(info) => unsafe {
-            (*info.as_ptr()).headers.iter_mut().map(|h| {
+            let info = &mut *info.as_ptr();
+            let num_headers = (info.num_headers.max(0) as usize).min(info.headers.len());
+            info.headers[..num_headers].iter_mut().map(|h| {
                 Header { ptr: h, _marker: marker::PhantomData }
             }).collect()
         },
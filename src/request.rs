@@ -0,0 +1,314 @@
+//! Civet-specific views of a request.
+//!
+//! `CivetRequestExt` is implemented for every `conduit::Request`, so these
+//! are available to any handler running under civet.
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::slice;
+use std::time::Instant;
+#[cfg(feature = "secure-cookies")]
+use std::sync::Arc;
+
+use conduit::{Host, Request};
+
+use raw::{self, RequestInfo};
+use tls::{self, ClientCertificate};
+
+#[cfg(feature = "secure-cookies")]
+use cookie::{CookieJar, Key};
+
+pub trait CivetRequestExt {
+    /// The parsed query string.
+    fn query(&self) -> Query;
+
+    /// The cookies sent with the request, from every `Cookie` header.
+    fn cookies(&self) -> Cookies;
+
+    /// `None` unless the server was configured with
+    /// `Config::decode_url(false)`, for handlers that deal with
+    /// percent-encoding themselves. Then it is the request target exactly as
+    /// the client sent it: query string included, nothing percent-decoded,
+    /// and repeated slashes and `..` left alone, unlike `path` and
+    /// `local_uri`.
+    fn request_uri(&self) -> Option<String>;
+
+    /// The path of the request target on this server, percent-decoded only
+    /// if civetweb decodes targets (see `Config::decode_url`). For a target
+    /// in absolute form (`GET http://host/path`) this is the part after the
+    /// host; it is `None` for `OPTIONS *`.
+    fn local_uri(&self) -> Option<String>;
+
+    /// The user a `civet::auth` handler, or civetweb itself, authenticated
+    /// the request as, if any.
+    fn remote_user(&self) -> Option<String>;
+
+    /// The ID civet gave the request, which is also sent back in the
+    /// `X-Request-Id` response header. See `Config::trust_request_id`.
+    fn request_id(&self) -> Option<String>;
+
+    /// The trailer fields the client sent after a chunked body, as name and
+    /// value pairs. They are only known once the body has been read to its
+    /// end, and are `None` before that or if the body wasn't chunked.
+    fn trailers(&self) -> Option<&[(String, String)]>;
+
+    /// The port the client addressed, which `host` leaves out for names:
+    /// the one in the `Host` header, or the scheme's default.
+    fn host_port(&self) -> u16;
+
+    /// The address of our end of the connection.
+    fn local_addr(&self) -> Option<SocketAddr>;
+
+    /// The certificate the client authenticated with, on HTTPS connections
+    /// with `Config::ssl_verify_peer` on.
+    fn client_certificate(&self) -> Option<ClientCertificate>;
+
+    /// The host name an HTTPS client asked for through SNI. Only available
+    /// with the `tls` feature.
+    fn server_name(&self) -> Option<String>;
+
+    /// When the handler's time is up, if `Config::handler_deadline` applies
+    /// to the request.
+    fn deadline(&self) -> Option<Instant>;
+
+    /// Which of the listeners set up with `Config::port` and
+    /// `Config::listen` accepted the connection, counting from 0.
+    fn listener(&self) -> Option<usize>;
+
+    /// The cookies, for reading signed and encrypted values. `None` unless
+    /// the server was configured with `Config::cookie_key`.
+    #[cfg(feature = "secure-cookies")]
+    fn cookie_jar(&self) -> Option<CookieJar>;
+}
+
+impl<R: Request + ?Sized> CivetRequestExt for R {
+    fn query(&self) -> Query {
+        Query::parse(self.query_string().unwrap_or(""))
+    }
+
+    fn cookies(&self) -> Cookies {
+        let mut cookies = Cookies { pairs: Vec::new() };
+        for (name, values) in self.headers().all() {
+            if name.eq_ignore_ascii_case("Cookie") {
+                for value in values {
+                    cookies.add(value);
+                }
+            }
+        }
+        cookies
+    }
+
+    fn request_uri(&self) -> Option<String> {
+        if self.extensions().find::<::UndecodedTarget>().is_none() {
+            return None
+        }
+        match self.extensions().find::<::RawConnection>() {
+            Some(&::RawConnection(ref conn)) => {
+                raw::request_target(conn).map(|s| s.to_string())
+            }
+            None => None,
+        }
+    }
+
+    fn local_uri(&self) -> Option<String> {
+        with_info(self, |info| info.local_uri().map(|s| s.to_string()))
+    }
+
+    fn remote_user(&self) -> Option<String> {
+        if let Some(&::RemoteUser(ref user)) = self.extensions().find::<::RemoteUser>() {
+            return Some(user.clone())
+        }
+        with_info(self, |info| info.remote_user().map(|s| s.to_string()))
+    }
+
+    fn request_id(&self) -> Option<String> {
+        self.extensions().find::<::RequestId>().map(|&::RequestId(ref id)| id.clone())
+    }
+
+    fn trailers(&self) -> Option<&[(String, String)]> {
+        self.extensions().find::<::Trailers>().map(|&::Trailers(ref trailers)| &trailers[..])
+    }
+
+    fn host_port(&self) -> u16 {
+        match self.host() {
+            Host::Socket(addr) => addr.port(),
+            Host::Name(..) => {
+                ::requested_host(self)
+                    .and_then(::split_host)
+                    .and_then(|(_, port)| port)
+                    .unwrap_or(::default_port(self.scheme()))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self.extensions().find::<::RawConnection>() {
+            Some(&::RawConnection(ref conn)) => raw::local_addr(conn),
+            None => None,
+        }
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        match self.extensions().find::<::RawConnection>() {
+            Some(&::RawConnection(ref conn)) => tls::client_certificate(conn),
+            None => None,
+        }
+    }
+
+    fn server_name(&self) -> Option<String> {
+        match self.extensions().find::<::RawConnection>() {
+            Some(&::RawConnection(ref conn)) => tls::server_name(conn),
+            None => None,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.extensions().find::<::Deadline>().map(|&::Deadline(deadline)| deadline)
+    }
+
+    fn listener(&self) -> Option<usize> {
+        self.extensions().find::<::Listener>().map(|&::Listener(index)| index)
+    }
+
+    #[cfg(feature = "secure-cookies")]
+    fn cookie_jar(&self) -> Option<CookieJar> {
+        self.extensions().find::<Arc<Key>>()
+            .map(|key| CookieJar::new(key, self.cookies()))
+    }
+}
+
+fn with_info<R, F>(req: &R, f: F) -> Option<String>
+    where R: Request + ?Sized, F: FnOnce(&RequestInfo) -> Option<String>
+{
+    match req.extensions().find::<::RawConnection>() {
+        Some(&::RawConnection(ref conn)) => {
+            raw::get_request_info(conn).and_then(|info| f(&info))
+        }
+        None => None,
+    }
+}
+
+/// The variables of a query string, following the rules of civetweb's
+/// `mg_get_var2`: names match case-insensitively, values are form-decoded,
+/// and a name may appear several times.
+pub struct Query<'a> {
+    pairs: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+}
+
+impl<'a> Query<'a> {
+    /// Parses `a=1&b=2`. Pieces without an `=` are ignored. Nothing is
+    /// copied unless it has to be decoded.
+    pub fn parse(query: &'a str) -> Query<'a> {
+        let pairs = query.split('&').filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => {
+                    Some((::url_decode(name, true), ::url_decode(value, true)))
+                }
+                _ => None,
+            }
+        }).collect();
+        Query { pairs: pairs }
+    }
+
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    /// Every value of `name`, in order.
+    pub fn get_all<'b>(&'b self, name: &'b str) -> Values<'b, 'a> {
+        Values { pairs: self.pairs.iter(), name: name }
+    }
+
+    /// All of the variables, in order.
+    pub fn iter(&self) -> slice::Iter<(Cow<'a, str>, Cow<'a, str>)> {
+        self.pairs.iter()
+    }
+}
+
+pub struct Values<'b, 'a: 'b> {
+    pairs: slice::Iter<'b, (Cow<'a, str>, Cow<'a, str>)>,
+    name: &'b str,
+}
+
+impl<'b, 'a> Iterator for Values<'b, 'a> {
+    type Item = &'b str;
+
+    fn next(&mut self) -> Option<&'b str> {
+        let name = self.name;
+        self.pairs.by_ref()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| &v[..])
+    }
+}
+
+/// Request cookies, following the rules of civetweb's `mg_get_cookie`:
+/// names match case-insensitively and quoted values are unquoted, but
+/// values are otherwise left as sent.
+pub struct Cookies<'a> {
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Cookies<'a> {
+    /// Parses the value of a single `Cookie` header.
+    pub fn parse(header: &'a str) -> Cookies<'a> {
+        let mut cookies = Cookies { pairs: Vec::new() };
+        cookies.add(header);
+        cookies
+    }
+
+    fn add(&mut self, header: &'a str) {
+        for pair in header.split(';') {
+            let mut parts = pair.trim().splitn(2, '=');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                let value = if value.len() > 1 && value.starts_with('"') &&
+                               value.ends_with('"') {
+                    &value[1..value.len() - 1]
+                } else {
+                    value
+                };
+                self.pairs.push((name, value));
+            }
+        }
+    }
+
+    /// The value of the first cookie called `name`.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.pairs.iter()
+            .find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, v)| v)
+    }
+
+    /// All of the cookies, in order.
+    pub fn iter(&self) -> slice::Iter<(&'a str, &'a str)> {
+        self.pairs.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Query, Cookies};
+
+    #[test]
+    fn query_values() {
+        let query = Query::parse("a=1&B=two+words&a=%C3%A9%2&flag&c=");
+        assert_eq!(query.get("a"), Some("1"));
+        assert_eq!(query.get("b"), Some("two words"));
+        assert_eq!(query.get_all("A").collect::<Vec<_>>(), vec!["1", "\u{e9}%2"]);
+        assert_eq!(query.get("c"), Some(""));
+        assert_eq!(query.get("flag"), None);
+        assert_eq!(query.iter().count(), 4);
+    }
+
+    #[test]
+    fn cookie_values() {
+        let cookies = Cookies::parse("session=abc; Theme=\"dark\";lang=en");
+        assert_eq!(cookies.get("session"), Some("abc"));
+        assert_eq!(cookies.get("theme"), Some("dark"));
+        assert_eq!(cookies.get("lang"), Some("en"));
+        assert_eq!(cookies.get("sess"), None);
+    }
+}
@@ -0,0 +1,45 @@
+//! Telling requests apart in logs.
+//!
+//! Each request gets an ID, which handlers see through
+//! `CivetRequestExt::request_id` and clients in the `X-Request-Id` response
+//! header. With `Config::trust_request_id` on, a well-formed ID the client
+//! (or a proxy in front of the server) sent is used instead, so one request
+//! can be followed across services.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const HEADER: &'static str = "X-Request-Id";
+
+/// The longest incoming ID we'll take on.
+const MAX_LEN: usize = 200;
+
+/// Hands out IDs unique to this server: a random prefix chosen when it
+/// starts, then a counter.
+pub struct Generator {
+    prefix: String,
+    next: AtomicUsize,
+}
+
+impl Generator {
+    pub fn new() -> Generator {
+        let random = RandomState::new().build_hasher().finish();
+        Generator {
+            prefix: format!("{:016x}", random),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn next(&self) -> String {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{:x}", self.prefix, n)
+    }
+}
+
+/// Whether `id` is safe to log and echo back: printable ASCII without
+/// spaces, and not too long.
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN &&
+        id.bytes().all(|b| b > b' ' && b < 0x7f)
+}
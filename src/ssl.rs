@@ -0,0 +1,126 @@
+//! Taking over civetweb's `SSL_CTX` so that certificates can change while
+//! the server runs.
+//!
+//! civetweb creates one context and uses it for every connection. We hook a
+//! servername callback into it, which runs early in each handshake and moves
+//! the connection over to whichever context we currently want it served
+//! from: the one for the name the client asked for through SNI if there is
+//! one, otherwise the default. OpenSSL checks client certificates against
+//! the store of whichever context the connection ends up on, so ours are
+//! set up to verify clients the way `Config::ssl_verify_peer` told civetweb
+//! to.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use libc::c_void;
+use openssl::error::ErrorStack;
+use openssl::ssl::{SniError, SslContext, SslContextBuilder, SslFiletype};
+use openssl::ssl::{NameType, SslMethod, SslRef, SslVerifyMode};
+use openssl_sys;
+
+/// Where the authorities client certificates must be signed by are, and how
+/// long a chain may be, with `Config::ssl_verify_peer` on.
+pub struct Verify {
+    pub ca_file: Option<PathBuf>,
+    pub ca_path: Option<PathBuf>,
+    pub depth: Option<u32>,
+}
+
+pub struct Certificates {
+    current: RwLock<Option<SslContext>>,
+    hosts: HashMap<String, SslContext>,
+    verify: Option<Verify>,
+}
+
+impl Certificates {
+    /// Loads the certificates for `Config::tls_host`.
+    pub fn new(hosts: &[(String, PathBuf, PathBuf)], verify: Option<Verify>)
+               -> io::Result<Certificates> {
+        let mut contexts = HashMap::new();
+        for &(ref name, ref cert, ref key) in hosts {
+            let context = try!(load(cert, key, verify.as_ref()).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("invalid certificate for `{}`: {}",
+                                       name, e))
+            }));
+            contexts.insert(::host_key(name), context);
+        }
+        Ok(Certificates {
+            current: RwLock::new(None),
+            hosts: contexts,
+            verify: verify,
+        })
+    }
+
+    /// Serves new handshakes that aren't for a `tls_host` with the
+    /// certificate chain in `cert` and the private key in `key`.
+    pub fn reload(&self, cert: &Path, key: &Path) -> io::Result<()> {
+        let context = try!(load(cert, key, self.verify.as_ref()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        }));
+        *self.current.write().unwrap() = Some(context);
+        Ok(())
+    }
+
+    fn select(&self, ssl: &mut SslRef) -> Result<(), ErrorStack> {
+        let host = ssl.servername(NameType::HOST_NAME)
+            .and_then(|name| self.hosts.get(&::host_key(name)))
+            .cloned();
+        if let Some(context) = host {
+            return ssl.set_ssl_context(&context)
+        }
+        match *self.current.read().unwrap() {
+            Some(ref context) => ssl.set_ssl_context(context),
+            None => Ok(()),
+        }
+    }
+}
+
+fn load(cert: &Path, key: &Path, verify: Option<&Verify>)
+        -> Result<SslContext, ErrorStack> {
+    let mut builder = try!(SslContextBuilder::new(SslMethod::tls()));
+    try!(builder.set_certificate_chain_file(cert));
+    try!(builder.set_private_key_file(key, SslFiletype::PEM));
+    try!(builder.check_private_key());
+    try!(builder.set_session_id_context(b"civet"));
+    // As civetweb sets up its own context.
+    if let Some(verify) = verify {
+        try!(builder.load_verify_locations(verify.ca_file.as_ref().map(|p| &**p),
+                                           verify.ca_path.as_ref().map(|p| &**p)));
+        builder.set_verify(SslVerifyMode::PEER |
+                           SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        if let Some(depth) = verify.depth {
+            builder.set_verify_depth(depth);
+        }
+    }
+    Ok(builder.build())
+}
+
+/// Installs the callback on civetweb's context, which keeps `certificates`
+/// alive for as long as the context is.
+pub fn init(ssl_ctx: *mut c_void,
+            certificates: &Arc<Certificates>) -> Result<(), ErrorStack> {
+    let ssl_ctx = ssl_ctx as *mut openssl_sys::SSL_CTX;
+    // The builder hands its reference back when dropped; civetweb keeps
+    // its own.
+    let mut builder = unsafe {
+        try!(cvt(openssl_sys::SSL_CTX_up_ref(ssl_ctx)));
+        SslContextBuilder::from_ptr(ssl_ctx)
+    };
+    let certificates = certificates.clone();
+    builder.set_servername_callback(move |ssl, _alert| {
+        certificates.select(ssl).map_err(|e| {
+            error!("failed to switch TLS certificates: {}", e);
+            SniError::ALERT_FATAL
+        })
+    });
+    drop(builder.build());
+    Ok(())
+}
+
+fn cvt(r: i32) -> Result<(), ErrorStack> {
+    if r <= 0 { Err(ErrorStack::get()) } else { Ok(()) }
+}
@@ -0,0 +1,153 @@
+//! Counting what the server does, for `Server::stats`.
+//!
+//! civet is built against civetweb 1.9.1, which civet-sys's build.rs checks
+//! for. That release has no `mg_get_context_info` or
+//! `mg_get_connection_info`. Its one report, `mg_get_system_info`, is plain
+//! text about the build and the host. So nothing here comes from civetweb's
+//! JSON: every figure is counted as requests pass through
+//! `internal_handler`, apart from the connections `Config::shed_load`
+//! turns away, which civet.c counts as they are accepted. Figures only
+//! civetweb could know aren't reported, such as open keep-alive connections
+//! that are between requests.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency buckets, in milliseconds: the Prometheus
+/// client libraries' defaults.
+const LATENCY_BOUNDS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500,
+                                   5000, 10000];
+
+/// A snapshot of the server's statistics.
+#[derive(Clone, Debug)]
+pub struct ServerStats {
+    /// How long the server has been running.
+    pub uptime: Duration,
+    /// How many worker threads civetweb has, each handling one connection
+    /// at a time.
+    pub threads: u32,
+    /// How many requests handlers are working on right now.
+    pub active_requests: u64,
+    /// How many requests have been answered, however that went.
+    pub requests: u64,
+    /// How many responses there have been for each request method and
+    /// status code.
+    pub responses: BTreeMap<(String, u32), u64>,
+    /// Requests turned away by `Config::access_control`.
+    pub denied_requests: u64,
+    /// Connections turned away by `Config::shed_load` because the
+    /// `Config::connection_queue` was full.
+    pub shed_requests: u64,
+    /// Bytes of request bodies read.
+    pub bytes_read: u64,
+    /// Bytes of responses written, headers included.
+    pub bytes_written: u64,
+    /// How long requests took, from a worker picking them up to the last
+    /// byte of the response.
+    pub latency: Histogram,
+}
+
+impl ServerStats {
+    /// How many responses there have been with `status`, for any method.
+    pub fn status_count(&self, status: u32) -> u64 {
+        self.responses.iter()
+            .filter(|&(&(_, s), _)| s == status)
+            .map(|(_, &count)| count)
+            .sum()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    /// Upper bounds, and how many observations were no larger. As with
+    /// Prometheus, the counts are cumulative; observations above the last
+    /// bound only show up in `count`.
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+pub struct Recorder {
+    started: Instant,
+    threads: u32,
+    pub active: AtomicUsize,
+    pub denied: AtomicUsize,
+    pub shed: AtomicUsize,
+    counts: Mutex<Counts>,
+}
+
+struct Counts {
+    responses: BTreeMap<(String, u32), u64>,
+    bytes_read: u64,
+    bytes_written: u64,
+    latency: [u64; 11],
+    latency_count: u64,
+    latency_sum: Duration,
+}
+
+impl Recorder {
+    pub fn new(threads: u32) -> Recorder {
+        Recorder {
+            started: Instant::now(),
+            threads: threads,
+            active: AtomicUsize::new(0),
+            denied: AtomicUsize::new(0),
+            shed: AtomicUsize::new(0),
+            counts: Mutex::new(Counts {
+                responses: BTreeMap::new(),
+                bytes_read: 0,
+                bytes_written: 0,
+                latency: [0; 11],
+                latency_count: 0,
+                latency_sum: Duration::new(0, 0),
+            }),
+        }
+    }
+
+    /// Records a finished request. `status` is `None` if no response could
+    /// be sent.
+    pub fn record(&self, method: &str, status: Option<u32>, bytes_read: u64,
+                  bytes_written: u64, elapsed: Duration) {
+        let millis = elapsed.as_secs() * 1000 +
+                     elapsed.subsec_nanos() as u64 / 1_000_000;
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(status) = status {
+            *counts.responses.entry((method.to_string(), status))
+                             .or_insert(0) += 1;
+        }
+        counts.bytes_read += bytes_read;
+        counts.bytes_written += bytes_written;
+        for (bound, count) in LATENCY_BOUNDS.iter().zip(counts.latency.iter_mut()) {
+            if millis <= *bound {
+                *count += 1;
+            }
+        }
+        counts.latency_count += 1;
+        counts.latency_sum += elapsed;
+    }
+
+    pub fn snapshot(&self) -> ServerStats {
+        let counts = self.counts.lock().unwrap();
+        let buckets = LATENCY_BOUNDS.iter().zip(counts.latency.iter())
+            .map(|(&bound, &count)| (Duration::from_millis(bound), count))
+            .collect();
+        ServerStats {
+            uptime: self.started.elapsed(),
+            threads: self.threads,
+            active_requests: self.active.load(Ordering::SeqCst) as u64,
+            requests: counts.latency_count,
+            responses: counts.responses.clone(),
+            denied_requests: self.denied.load(Ordering::Relaxed) as u64,
+            shed_requests: self.shed.load(Ordering::Relaxed) as u64,
+            bytes_read: counts.bytes_read,
+            bytes_written: counts.bytes_written,
+            latency: Histogram {
+                buckets: buckets,
+                count: counts.latency_count,
+                sum: counts.latency_sum,
+            },
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! HTTPS support.
+//!
+//! civetweb does the TLS itself, loading OpenSSL at runtime, so serving
+//! HTTPS only needs `Config::ssl_certificate` and `Config::listen_tls`. The
+//! `tls` feature links OpenSSL into the Rust side as well, for the things
+//! civetweb can't do on its own.
+
+#[cfg(feature = "tls")]
+use foreign_types::ForeignTypeRef;
+#[cfg(feature = "tls")]
+use openssl::ssl::{NameType, SslRef};
+#[cfg(feature = "tls")]
+use openssl_sys;
+
+use raw;
+
+/// A certificate the client presented and civetweb verified, with
+/// `Config::ssl_verify_peer` on.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    /// The subject, in OpenSSL's one-line form, like `/O=Example/CN=client`.
+    pub subject: String,
+    pub issuer: String,
+    /// The serial number, in hex.
+    pub serial: String,
+    /// The SHA-1 fingerprint of the certificate, in hex.
+    pub fingerprint: String,
+    /// The certificate itself, PEM-encoded. Only available with the `tls`
+    /// feature.
+    pub pem: Option<String>,
+}
+
+pub fn client_certificate(conn: &raw::Connection) -> Option<ClientCertificate> {
+    let info = match raw::get_request_info(conn) {
+        Some(info) => info,
+        None => return None,
+    };
+    info.client_cert().map(|cert| {
+        let string = |s: Option<&str>| s.unwrap_or("").to_string();
+        ClientCertificate {
+            subject: string(cert.subject()),
+            issuer: string(cert.issuer()),
+            serial: string(cert.serial()),
+            fingerprint: string(cert.fingerprint()),
+            pem: peer_pem(conn),
+        }
+    })
+}
+
+/// The host name the client asked for through SNI.
+#[cfg(feature = "tls")]
+pub fn server_name(conn: &raw::Connection) -> Option<String> {
+    ssl(conn).and_then(|ssl| ssl.servername(NameType::HOST_NAME))
+        .map(|name| name.to_string())
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn server_name(_conn: &raw::Connection) -> Option<String> {
+    None
+}
+
+#[cfg(feature = "tls")]
+fn peer_pem(conn: &raw::Connection) -> Option<String> {
+    ssl(conn).and_then(|ssl| ssl.peer_certificate())
+        .and_then(|cert| cert.to_pem().ok())
+        .and_then(|pem| String::from_utf8(pem).ok())
+}
+
+#[cfg(not(feature = "tls"))]
+fn peer_pem(_conn: &raw::Connection) -> Option<String> {
+    None
+}
+
+#[cfg(feature = "tls")]
+fn ssl(conn: &raw::Connection) -> Option<&SslRef> {
+    let ssl = raw::ssl(conn) as *mut openssl_sys::SSL;
+    if ssl.is_null() {
+        None
+    } else {
+        Some(unsafe { SslRef::from_ptr(ssl) })
+    }
+}
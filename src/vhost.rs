@@ -0,0 +1,55 @@
+//! Serving several sites from one server.
+//!
+//! `VirtualHosts` is a handler that passes each request on to the handler
+//! for the host it is addressed to. Pair it with `Config::tls_host` to give
+//! each site its own certificate.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use conduit::{Handler, Host, Request, Response};
+
+use request::CivetRequestExt;
+
+pub struct VirtualHosts {
+    hosts: HashMap<String, Box<Handler>>,
+    default: Box<Handler>,
+}
+
+impl VirtualHosts {
+    /// Sends requests for hosts that weren't added with `host` to `default`.
+    pub fn new<H: Handler>(default: H) -> VirtualHosts {
+        VirtualHosts {
+            hosts: HashMap::new(),
+            default: Box::new(default),
+        }
+    }
+
+    /// Sends requests for `name` to `handler`. Names match
+    /// case-insensitively.
+    pub fn host<H: Handler>(&mut self, name: &str, handler: H)
+                            -> &mut VirtualHosts {
+        self.hosts.insert(::host_key(name), Box::new(handler));
+        self
+    }
+
+    /// The handler for `req`, picked by the name in its `Host` header, or
+    /// the name it asked for through SNI if the `Host` header doesn't give
+    /// one.
+    fn handler_for(&self, req: &Request) -> &Handler {
+        let name = match req.host() {
+            Host::Name(name) => Some(name.to_string()),
+            Host::Socket(..) => req.server_name(),
+        };
+        name.and_then(|name| self.hosts.get(&::host_key(&name)))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let handler = self.handler_for(req);
+        handler.call(req)
+    }
+}